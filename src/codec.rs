@@ -0,0 +1,550 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::message::{Message, NodeDiff};
+use crate::node::{Diff, Digest};
+use crate::value::Value;
+
+// Binary wire format, version 1:
+// * message: version byte, message tag byte, then the message fields.
+// * integers: unsigned LEB128 varints, with signed values zigzag encoded first.
+// * floats: 8 bytes, little endian IEEE 754.
+// * strings and sequences: varint length prefix, followed by the bytes or items.
+// * values and addresses: tag byte, followed by the variant payload.
+pub const VERSION: u8 = 1;
+
+const SYN: u8 = 1;
+const ACK: u8 = 2;
+const ACK2: u8 = 3;
+const PING: u8 = 4;
+const PING_REQ: u8 = 5;
+const PING_ACK: u8 = 6;
+
+const STRING: u8 = 1;
+const BOOLEAN: u8 = 2;
+const INTEGER: u8 = 3;
+const FLOAT: u8 = 4;
+const INTEGERS: u8 = 5;
+const FLOATS: u8 = 6;
+const TOMBSTONE: u8 = 7;
+
+const NO_ADDRESS: u8 = 0;
+const IPV4: u8 = 4;
+const IPV6: u8 = 6;
+
+const MAX_VARINT_LEN: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+  UnexpectedEnd,
+  UnsupportedVersion(u8),
+  InvalidTag(&'static str, u8),
+  InvalidVarint,
+  InvalidLength(u64),
+  InvalidUtf8,
+  TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::UnexpectedEnd => { write!(f, "unexpected end of input") }
+      Self::UnsupportedVersion(v) => { write!(f, "unsupported version {}", v) }
+      Self::InvalidTag(kind, t) => { write!(f, "invalid {} tag {}", kind, t) }
+      Self::InvalidVarint => { write!(f, "invalid varint") }
+      Self::InvalidLength(l) => { write!(f, "invalid length {}", l) }
+      Self::InvalidUtf8 => { write!(f, "invalid utf-8 string") }
+      Self::TrailingBytes(n) => { write!(f, "{} trailing bytes", n) }
+    }
+  }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+  let mut buffer = vec![VERSION];
+  match message {
+    Message::Syn { cluster, digest } => {
+      buffer.push(SYN);
+      put_string(&mut buffer, cluster);
+      put_digests(&mut buffer, digest);
+    }
+    Message::Ack { cluster, requests, diffs, backlog } => {
+      buffer.push(ACK);
+      put_string(&mut buffer, cluster);
+      put_digests(&mut buffer, requests);
+      put_node_diffs(&mut buffer, diffs);
+      put_varint(&mut buffer, *backlog);
+    }
+    Message::Ack2 { cluster, diffs, backlog } => {
+      buffer.push(ACK2);
+      put_string(&mut buffer, cluster);
+      put_node_diffs(&mut buffer, diffs);
+      put_varint(&mut buffer, *backlog);
+    }
+    Message::Ping { cluster, sequence } => {
+      buffer.push(PING);
+      put_string(&mut buffer, cluster);
+      put_varint(&mut buffer, *sequence);
+    }
+    Message::PingReq { cluster, sequence, target } => {
+      buffer.push(PING_REQ);
+      put_string(&mut buffer, cluster);
+      put_varint(&mut buffer, *sequence);
+      put_address(&mut buffer, &Some(*target));
+    }
+    Message::PingAck { cluster, sequence } => {
+      buffer.push(PING_ACK);
+      put_string(&mut buffer, cluster);
+      put_varint(&mut buffer, *sequence);
+    }
+  }
+  return buffer;
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Message, DecodeError> {
+  let mut reader = Reader::new(bytes);
+  let version = reader.byte()?;
+  if version != VERSION { return Err(DecodeError::UnsupportedVersion(version)); }
+
+  let message = match reader.byte()? {
+    SYN => {
+      let cluster = reader.string()?;
+      let digest = reader.digests()?;
+      Message::Syn { cluster, digest }
+    }
+    ACK => {
+      let cluster = reader.string()?;
+      let requests = reader.digests()?;
+      let diffs = reader.node_diffs()?;
+      let backlog = reader.varint()?;
+      Message::Ack { cluster, requests, diffs, backlog }
+    }
+    ACK2 => {
+      let cluster = reader.string()?;
+      let diffs = reader.node_diffs()?;
+      let backlog = reader.varint()?;
+      Message::Ack2 { cluster, diffs, backlog }
+    }
+    PING => {
+      let cluster = reader.string()?;
+      let sequence = reader.varint()?;
+      Message::Ping { cluster, sequence }
+    }
+    PING_REQ => {
+      let cluster = reader.string()?;
+      let sequence = reader.varint()?;
+      let target = reader.address()?.ok_or(DecodeError::InvalidTag("address", NO_ADDRESS))?;
+      Message::PingReq { cluster, sequence, target }
+    }
+    PING_ACK => {
+      let cluster = reader.string()?;
+      let sequence = reader.varint()?;
+      Message::PingAck { cluster, sequence }
+    }
+    tag => { return Err(DecodeError::InvalidTag("message", tag)); }
+  };
+
+  reader.finish()?;
+  return Ok(message);
+}
+
+pub fn encode_value(value: &Value) -> Vec<u8> {
+  let mut buffer = Vec::new();
+  put_value(&mut buffer, value);
+  return buffer;
+}
+
+pub fn decode_value(bytes: &[u8]) -> Result<Value, DecodeError> {
+  let mut reader = Reader::new(bytes);
+  let value = reader.value()?;
+  reader.finish()?;
+  return Ok(value);
+}
+
+// Encoded sizes, used to pack diffs into a byte budget.
+pub fn varint_len(n: u64) -> usize {
+  let bits = 64 - n.leading_zeros() as usize;
+  return usize::max(1, bits.div_ceil(7));
+}
+
+pub fn diff_len(diff: &Diff) -> usize {
+  let mut buffer = Vec::new();
+  put_diff(&mut buffer, diff);
+  return buffer.len();
+}
+
+pub fn digest_len(digest: &Digest) -> usize {
+  let mut buffer = Vec::new();
+  put_digest(&mut buffer, digest);
+  return buffer.len();
+}
+
+// Size of a node diff excluding its updates, with room for an update count up to `count`.
+pub fn node_diff_len(digest: &Digest, address: &Option<SocketAddr>, count: usize) -> usize {
+  let mut buffer = Vec::new();
+  put_digest(&mut buffer, digest);
+  put_address(&mut buffer, address);
+  return buffer.len() + varint_len(count as u64);
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut n: u64) {
+  while n >= 0x80 {
+    buffer.push((n as u8) | 0x80);
+    n >>= 7;
+  }
+  buffer.push(n as u8);
+}
+
+fn put_signed(buffer: &mut Vec<u8>, n: i64) {
+  put_varint(buffer, ((n << 1) ^ (n >> 63)) as u64);
+}
+
+fn put_float(buffer: &mut Vec<u8>, n: f64) {
+  buffer.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_string(buffer: &mut Vec<u8>, s: &str) {
+  put_varint(buffer, s.len() as u64);
+  buffer.extend_from_slice(s.as_bytes());
+}
+
+fn put_value(buffer: &mut Vec<u8>, value: &Value) {
+  match value {
+    Value::String(v) => { buffer.push(STRING); put_string(buffer, v); }
+    Value::Boolean(v) => { buffer.push(BOOLEAN); buffer.push(*v as u8); }
+    Value::Integer(v) => { buffer.push(INTEGER); put_signed(buffer, *v); }
+    Value::Float(v) => { buffer.push(FLOAT); put_float(buffer, *v); }
+    Value::Integers(v) => {
+      buffer.push(INTEGERS);
+      put_varint(buffer, v.len() as u64);
+      for &n in v { put_signed(buffer, n); }
+    }
+    Value::Floats(v) => {
+      buffer.push(FLOATS);
+      put_varint(buffer, v.len() as u64);
+      for &n in v { put_float(buffer, n); }
+    }
+    Value::Tombstone => { buffer.push(TOMBSTONE); }
+  }
+}
+
+fn put_address(buffer: &mut Vec<u8>, address: &Option<SocketAddr>) {
+  match address {
+    None => { buffer.push(NO_ADDRESS); }
+    Some(a) => {
+      match a.ip() {
+        IpAddr::V4(ip) => { buffer.push(IPV4); buffer.extend_from_slice(&ip.octets()); }
+        IpAddr::V6(ip) => { buffer.push(IPV6); buffer.extend_from_slice(&ip.octets()); }
+      }
+      buffer.extend_from_slice(&a.port().to_be_bytes());
+    }
+  }
+}
+
+fn put_digest(buffer: &mut Vec<u8>, (identifier, generation, sequence): &Digest) {
+  put_string(buffer, identifier);
+  put_varint(buffer, *generation);
+  put_varint(buffer, *sequence);
+}
+
+fn put_digests(buffer: &mut Vec<u8>, digests: &[Digest]) {
+  put_varint(buffer, digests.len() as u64);
+  for d in digests { put_digest(buffer, d); }
+}
+
+fn put_diff(buffer: &mut Vec<u8>, (key, (value, sequence)): &Diff) {
+  put_string(buffer, key);
+  put_value(buffer, value);
+  put_varint(buffer, *sequence);
+}
+
+fn put_node_diffs(buffer: &mut Vec<u8>, diffs: &[NodeDiff]) {
+  put_varint(buffer, diffs.len() as u64);
+  for (digest, updates, address) in diffs {
+    put_digest(buffer, digest);
+    put_varint(buffer, updates.len() as u64);
+    for u in updates { put_diff(buffer, u); }
+    put_address(buffer, address);
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8]) -> Self { Self { bytes, position: 0 } }
+
+  fn remaining(&self) -> usize { self.bytes.len() - self.position }
+
+  fn finish(&self) -> Result<(), DecodeError> {
+    match self.remaining() {
+      0 => { Ok(()) }
+      n => { Err(DecodeError::TrailingBytes(n)) }
+    }
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+    if n > self.remaining() { return Err(DecodeError::UnexpectedEnd); }
+    let slice = &self.bytes[self.position .. self.position + n];
+    self.position += n;
+    return Ok(slice);
+  }
+
+  fn byte(&mut self) -> Result<u8, DecodeError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn varint(&mut self) -> Result<u64, DecodeError> {
+    let mut n = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+      let b = self.byte()?;
+      let bits = (b & 0x7f) as u64;
+      // the tenth byte may only carry the single remaining bit
+      if i == MAX_VARINT_LEN - 1 && bits > 1 { return Err(DecodeError::InvalidVarint); }
+      n |= bits << (7 * i);
+      if b & 0x80 == 0 { return Ok(n); }
+    }
+    return Err(DecodeError::InvalidVarint);
+  }
+
+  fn signed(&mut self) -> Result<i64, DecodeError> {
+    let n = self.varint()?;
+    Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+  }
+
+  fn float(&mut self) -> Result<f64, DecodeError> {
+    let bytes = self.take(8)?;
+    Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+  }
+
+  // Read a sequence length, rejecting any that could not possibly fit in the
+  // remaining input so corrupt lengths never trigger large allocations.
+  fn length(&mut self, min_item_size: usize) -> Result<usize, DecodeError> {
+    let length = self.varint()?;
+    if length > (self.remaining() / min_item_size) as u64 {
+      return Err(DecodeError::InvalidLength(length));
+    }
+    return Ok(length as usize);
+  }
+
+  fn string(&mut self) -> Result<String, DecodeError> {
+    let length = self.length(1)?;
+    let bytes = self.take(length)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+  }
+
+  fn value(&mut self) -> Result<Value, DecodeError> {
+    match self.byte()? {
+      STRING => { Ok(Value::String(self.string()?)) }
+      BOOLEAN => {
+        match self.byte()? {
+          0 => { Ok(Value::Boolean(false)) }
+          1 => { Ok(Value::Boolean(true)) }
+          b => { Err(DecodeError::InvalidTag("boolean", b)) }
+        }
+      }
+      INTEGER => { Ok(Value::Integer(self.signed()?)) }
+      FLOAT => { Ok(Value::Float(self.float()?)) }
+      INTEGERS => {
+        let length = self.length(1)?;
+        let values = (0..length).map(|_| self.signed()).collect::<Result<_, _>>()?;
+        Ok(Value::Integers(values))
+      }
+      FLOATS => {
+        let length = self.length(8)?;
+        let values = (0..length).map(|_| self.float()).collect::<Result<_, _>>()?;
+        Ok(Value::Floats(values))
+      }
+      TOMBSTONE => { Ok(Value::Tombstone) }
+      tag => { Err(DecodeError::InvalidTag("value", tag)) }
+    }
+  }
+
+  fn address(&mut self) -> Result<Option<SocketAddr>, DecodeError> {
+    let ip: IpAddr = match self.byte()? {
+      NO_ADDRESS => { return Ok(None); }
+      IPV4 => {
+        let octets: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ipv4Addr::from(octets).into()
+      }
+      IPV6 => {
+        let octets: [u8; 16] = self.take(16)?.try_into().unwrap();
+        Ipv6Addr::from(octets).into()
+      }
+      tag => { return Err(DecodeError::InvalidTag("address", tag)); }
+    };
+    let port = u16::from_be_bytes(self.take(2)?.try_into().unwrap());
+    return Ok(Some(SocketAddr::new(ip, port)));
+  }
+
+  fn digest(&mut self) -> Result<Digest, DecodeError> {
+    Ok((self.string()?, self.varint()?, self.varint()?))
+  }
+
+  fn digests(&mut self) -> Result<Vec<Digest>, DecodeError> {
+    let length = self.length(3)?;
+    (0..length).map(|_| self.digest()).collect()
+  }
+
+  fn diff(&mut self) -> Result<Diff, DecodeError> {
+    let key = self.string()?;
+    let value = self.value()?;
+    let sequence = self.varint()?;
+    Ok((key, (value, sequence)))
+  }
+
+  fn node_diffs(&mut self) -> Result<Vec<NodeDiff>, DecodeError> {
+    let length = self.length(5)?;
+    (0..length).map(|_| {
+      let digest = self.digest()?;
+      let count = self.length(4)?;
+      let updates = (0..count).map(|_| self.diff()).collect::<Result<_, _>>()?;
+      let address = self.address()?;
+      Ok((digest, updates, address))
+    }).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils;
+  use crate::utils::testing::{addr, addr_from};
+
+  fn values() -> Vec<Value> {
+    vec![
+      "".into(),
+      "some string ✓".into(),
+      true.into(),
+      false.into(),
+      0i64.into(),
+      i64::MIN.into(),
+      i64::MAX.into(),
+      (-42i64).into(),
+      1.5f64.into(),
+      f64::NEG_INFINITY.into(),
+      vec![0i64, -1, 1, i64::MIN, i64::MAX].into(),
+      Vec::<i64>::new().into(),
+      vec![0.0f64, -2.25, 1e300].into(),
+      Value::Tombstone,
+    ]
+  }
+
+  fn messages() -> Vec<Message> {
+    let diffs: Vec<NodeDiff> = vec![
+      (("a".into(), 1, 3), vec![("k1".into(), (1.into(), 1)), ("k2".into(), ("v".into(), 3))], Some(addr())),
+      (("b".into(), 1_700_000_000, u64::MAX), vec![("k1".into(), (vec![1.5f64].into(), u64::MAX))], None),
+      (("c".into(), 0, 1), vec![], Some(addr_from("[2001:db8::1]:8080"))),
+    ];
+    vec![
+      Message::Syn { cluster: "cluster".into(), digest: vec![] },
+      Message::Syn { cluster: "cluster".into(), digest: vec![("a".into(), 1, 1), ("b".into(), 2, 300)] },
+      Message::Ack { cluster: "cluster".into(), requests: vec![("c".into(), 0, 0)], diffs: diffs.clone(), backlog: 0 },
+      Message::Ack2 { cluster: "".into(), diffs, backlog: 1000 },
+      Message::Ping { cluster: "cluster".into(), sequence: 1 },
+      Message::PingReq { cluster: "cluster".into(), sequence: 300, target: addr_from("[2001:db8::1]:8080") },
+      Message::PingAck { cluster: "cluster".into(), sequence: u64::MAX },
+    ]
+  }
+
+  #[test]
+  fn test_value_round_trip() {
+    for value in values() {
+      assert_eq!(decode_value(&encode_value(&value)), Ok(value));
+    }
+  }
+
+  #[test]
+  fn test_message_round_trip() {
+    for message in messages() {
+      assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+  }
+
+  #[test]
+  fn test_encoding_is_stable() {
+    let syn = Message::Syn { cluster: "c".into(), digest: vec![("a".into(), 5, 300)] };
+    assert_eq!(encode(&syn), [1, 1, 1, b'c', 1, 1, b'a', 5, 0xac, 0x02]);
+    assert_eq!(encode_value(&(-1i64).into()), [3, 1]);
+    assert_eq!(encode_value(&true.into()), [2, 1]);
+  }
+
+  #[test]
+  fn test_encoded_lengths() {
+    for n in [0, 1, 127, 128, 300, 16383, 16384, u64::MAX] {
+      let mut buffer = Vec::new();
+      put_varint(&mut buffer, n);
+      assert_eq!(varint_len(n), buffer.len());
+    }
+
+    let diff: Diff = ("key".into(), ("value".into(), 300));
+    assert_eq!(diff_len(&diff), 1 + 3 + 1 + 1 + 5 + 2);
+
+    for message in messages() {
+      if let Message::Ack2 { cluster, diffs, backlog } = &message {
+        let mut expected = 2 + 1 + cluster.len() + varint_len(diffs.len() as u64) + varint_len(*backlog);
+        for (digest, updates, address) in diffs {
+          expected += node_diff_len(digest, address, updates.len());
+          expected += updates.iter().map(diff_len).sum::<usize>();
+        }
+        assert_eq!(encode(&message).len(), expected);
+      }
+    }
+  }
+
+  #[test]
+  fn test_decode_rejects_truncated_input() {
+    for message in messages() {
+      let bytes = encode(&message);
+      for n in 0..bytes.len() {
+        assert!(decode(&bytes[0..n]).is_err());
+      }
+    }
+  }
+
+  #[test]
+  fn test_decode_errors() {
+    assert_eq!(decode(&[]), Err(DecodeError::UnexpectedEnd));
+    assert_eq!(decode(&[2, 1, 0, 0]), Err(DecodeError::UnsupportedVersion(2)));
+    assert_eq!(decode(&[1, 9]), Err(DecodeError::InvalidTag("message", 9)));
+    assert_eq!(decode(&[1, 5, 0, 1, 0]), Err(DecodeError::InvalidTag("address", 0)));
+    assert_eq!(decode(&[1, 1, 0, 0, 0]), Err(DecodeError::TrailingBytes(1)));
+    assert_eq!(decode(&[1, 1, 2, 0xff, 0xfe, 0]), Err(DecodeError::InvalidUtf8));
+    assert_eq!(decode(&[1, 1, 0, 0xff, 0xff, 0x03]), Err(DecodeError::InvalidLength(65535)));
+    assert_eq!(
+      decode(&[1, 1, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+      Err(DecodeError::InvalidVarint)
+    );
+    assert_eq!(
+      decode(&[1, 1, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]),
+      Err(DecodeError::InvalidVarint)
+    );
+    assert_eq!(decode_value(&[8]), Err(DecodeError::InvalidTag("value", 8)));
+    assert_eq!(decode_value(&[2, 2]), Err(DecodeError::InvalidTag("boolean", 2)));
+    assert_eq!(decode_value(&[6, 2, 0, 0, 0, 0, 0, 0, 0, 0]), Err(DecodeError::InvalidLength(2)));
+    assert_eq!(decode(&[1, 3, 0, 1, 1, b'a', 0, 0, 0, 5, 0]), Err(DecodeError::InvalidTag("address", 5)));
+  }
+
+  #[test]
+  fn test_decode_random_input_does_not_panic() {
+    let mut rng = utils::rng(Some(42));
+    let samples = messages().iter().map(encode).collect::<Vec<_>>();
+    for _ in 0..10_000 {
+      // corrupt some bytes of valid messages
+      let mut bytes = utils::rand::choose(&mut rng, &samples).clone();
+      for _ in 0..rng.rand_range(1..4) {
+        let i = rng.rand_range(0..bytes.len() as u64) as usize;
+        bytes[i] = rng.rand_u64() as u8;
+      }
+      let _ = decode(&bytes);
+
+      // and try completely random bytes
+      let length = rng.rand_range(0..64) as usize;
+      let bytes: Vec<u8> = (0..length).map(|_| rng.rand_u64() as u8).collect();
+      let _ = decode(&bytes);
+    }
+  }
+}
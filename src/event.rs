@@ -0,0 +1,25 @@
+use crate::value::Value;
+
+// Changes to the cluster, collected by `Gossip` for the application to drain.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+  // a new peer was discovered
+  Joined(String),
+  // a peer was heard from, after being new or inactive
+  Active(String),
+  // a peer was suspected of failing, by this node or another
+  Suspected(String),
+  // a suspected peer refuted it, by moving to a newer incarnation
+  Refuted(String),
+  // a peer's failure detector decided it has failed, or it failed a probe,
+  // or it was suspected for too long
+  Inactive(String),
+  // a peer left the cluster, rather than failing
+  Left(String),
+  // an inactive peer was discarded
+  Pruned(String),
+  // a peer restarted with a new generation, replacing its previous state
+  Restarted(String),
+  // a peer's value changed, with `None` when deleted
+  Changed { node: String, key: String, value: Option<Value> },
+}
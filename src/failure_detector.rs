@@ -1,5 +1,62 @@
+use std::collections::VecDeque;
+use std::fmt;
+
 use super::utils::Touch;
 
+// Judges whether a peer has failed, from the times updates arrive from it.
+pub trait Detector: fmt::Debug {
+  // record an update arriving now
+  fn update(&mut self);
+  // the suspicion the peer has failed, on a scale depending on the detector
+  fn phi(&self) -> f64;
+  fn failed(&self) -> bool;
+}
+
+// The distribution the intervals between updates are assumed to follow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+  Normal,
+  // suits bursty arrivals, with phi growing linearly over time
+  Exponential,
+}
+
+// How peers' failure detectors are created, chosen per cluster: the
+// timeout suits a LAN with steady intervals, and accrual a WAN with jitter.
+#[derive(Clone, Debug)]
+pub enum Strategy {
+  Ewma { threshold: f64, weight: f64, interval: f64 },
+  Accrual {
+    threshold: f64,
+    interval: f64,
+    window: usize,
+    distribution: Distribution,
+    min_standard_deviation: f64,
+  },
+  Timeout { timeout: f64 },
+  Custom(fn() -> Box<dyn Detector>),
+}
+
+impl Strategy {
+  pub fn detector(&self) -> Box<dyn Detector> {
+    match *self {
+      Strategy::Ewma { threshold, weight, interval } => {
+        Box::new(FailureDetector::new(threshold, weight, interval))
+      }
+      Strategy::Accrual { threshold, interval, window, distribution, min_standard_deviation } => {
+        Box::new(AccrualDetector::new(threshold, interval, window, distribution, min_standard_deviation))
+      }
+      Strategy::Timeout { timeout } => { Box::new(TimeoutDetector::new(timeout)) }
+      Strategy::Custom(create) => { create() }
+    }
+  }
+}
+
+impl Default for Strategy {
+  fn default() -> Self { Self::Ewma { threshold: 8.0, weight: 0.9, interval: 1.0 } }
+}
+
+// The time since the last update relative to exponentially weighted
+// averages of the intervals, so phi is a multiple of the usual interval.
 pub struct FailureDetector {
   threshold: f64,
   weight: f64,
@@ -19,7 +76,18 @@ impl FailureDetector {
     }
   }
 
-  pub fn update(&mut self) {
+  pub fn variance(&self) -> f64 {
+    // * Note: clamped, as rounding can leave it just below zero
+    f64::max(0.0, self.squared_interval - self.mean * self.mean)
+  }
+
+  fn standard_deviation(&self) -> f64 {
+    self.variance().sqrt()
+  }
+}
+
+impl Detector for FailureDetector {
+  fn update(&mut self) {
     let interval = self.touch.update();
     let weighted_interval = (1.0 - self.weight) * interval;
     self.mean = self.weight * self.mean + weighted_interval;
@@ -28,20 +96,12 @@ impl FailureDetector {
       weighted_interval * interval;
   }
 
-  pub fn variance(&self) -> f64 {
-    self.squared_interval - self.mean * self.mean
-  }
-
-  fn standard_deviation(&self) -> f64 {
-    self.variance().sqrt()
-  }
-
-  pub fn phi(&self) -> f64 {
+  fn phi(&self) -> f64 {
     let interval = self.touch.age();
     interval / (self.mean + 2.0 * self.standard_deviation())
   }
 
-  pub fn failed(&self) -> bool {
+  fn failed(&self) -> bool {
     self.phi() > self.threshold
   }
 }
@@ -50,7 +110,6 @@ impl Default for FailureDetector {
   fn default() -> Self { Self::new(8.0, 0.9, 1.0) }
 }
 
-use std::fmt;
 impl fmt::Debug for FailureDetector {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     f.debug_struct("FailtureDetector")
@@ -63,6 +122,112 @@ impl fmt::Debug for FailureDetector {
   }
 }
 
+// The phi accrual of Hayashibara et al: -log10 of the probability of an
+// update arriving this late, from the mean and deviation of the latest
+// `window` intervals, so a threshold of 8 means a 1e-8 chance of a false
+// positive.
+pub struct AccrualDetector {
+  threshold: f64,
+  window: usize,
+  distribution: Distribution,
+  min_standard_deviation: f64,
+  intervals: VecDeque<f64>,
+  touch: Touch,
+}
+
+impl AccrualDetector {
+  pub fn new(
+    threshold: f64, interval: f64, window: usize, distribution: Distribution, min_standard_deviation: f64
+  ) -> Self {
+    Self {
+      threshold,
+      window,
+      distribution,
+      min_standard_deviation,
+      intervals: VecDeque::from([interval]),
+      touch: Touch::now(),
+    }
+  }
+
+  pub fn mean(&self) -> f64 {
+    self.intervals.iter().sum::<f64>() / self.intervals.len() as f64
+  }
+
+  pub fn variance(&self) -> f64 {
+    let mean = self.mean();
+    self.intervals.iter().map(|i| (i - mean) * (i - mean)).sum::<f64>() / self.intervals.len() as f64
+  }
+}
+
+impl Detector for AccrualDetector {
+  fn update(&mut self) {
+    let interval = self.touch.update();
+    if self.intervals.len() >= self.window { self.intervals.pop_front(); }
+    self.intervals.push_back(interval);
+  }
+
+  fn phi(&self) -> f64 {
+    let interval = self.touch.age();
+    match self.distribution {
+      Distribution::Normal => {
+        let deviation = f64::max(self.variance().sqrt(), self.min_standard_deviation);
+        normal_phi(interval, self.mean(), deviation)
+      }
+      Distribution::Exponential => {
+        // -log10(e^(-t/mean))
+        interval / (self.mean() * std::f64::consts::LN_10)
+      }
+    }
+  }
+
+  fn failed(&self) -> bool {
+    self.phi() > self.threshold
+  }
+}
+
+impl fmt::Debug for AccrualDetector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("AccrualDetector")
+        .field("phi", &self.phi())
+        .field("failed", &self.failed())
+        .field("last", &self.touch)
+        .field("distribution", &self.distribution)
+        .field("mean", &self.mean())
+        .field("variance", &self.variance())
+        .finish()
+  }
+}
+
+// -log10 of the normal distribution's upper tail beyond the interval, using
+// a logistic approximation of the cumulative distribution that stays
+// accurate far into the tail.
+fn normal_phi(interval: f64, mean: f64, deviation: f64) -> f64 {
+  let y = (interval - mean) / deviation;
+  let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+  if interval > mean { return -(e / (1.0 + e)).log10(); }
+  return -(1.0 - 1.0 / (1.0 + e)).log10();
+}
+
+// Fails once there has been no update for the timeout, with phi the
+// fraction of it that has passed.
+#[derive(Debug)]
+pub struct TimeoutDetector {
+  timeout: f64,
+  touch: Touch,
+}
+
+impl TimeoutDetector {
+  pub fn new(timeout: f64) -> Self {
+    Self { timeout, touch: Touch::now() }
+  }
+}
+
+impl Detector for TimeoutDetector {
+  fn update(&mut self) { self.touch.reset(); }
+  fn phi(&self) -> f64 { self.touch.age() / self.timeout }
+  fn failed(&self) -> bool { self.phi() > 1.0 }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -109,6 +274,114 @@ mod tests {
     assert_is_close(d.phi(), 7.0588235, 1e-7);
   }
 
+  #[test]
+  fn test_with_steady_updates_still_fails() {
+    let mut d = FailureDetector::default();
+    for _ in 0..1000 {
+      advance_clock(0.5);
+      d.update();
+    }
+    assert!(d.variance() >= 0.0);
+    assert_eq!(d.failed(), false);
+
+    advance_clock(10.0);
+    assert_eq!(d.failed(), true);
+  }
+
+  fn accrual(window: usize, distribution: Distribution) -> AccrualDetector {
+    AccrualDetector::new(8.0, 1.0, window, distribution, 0.1)
+  }
+
+  #[test]
+  fn test_normal_accrual_phi_is_the_tail_probability() {
+    let mut d = accrual(10, Distribution::Normal);
+    for _ in 0..5 {
+      advance_clock(1.0);
+      d.update();
+    }
+    // at the mean, half of the updates would have arrived
+    advance_clock(1.0);
+    assert_is_close(d.phi(), std::f64::consts::LOG10_2, 1e-3);
+    // and with the minimum deviation, 84% one deviation later
+    advance_clock(0.1);
+    assert_is_close(d.phi(), 0.79968, 1e-3);
+    advance_clock(0.4);
+    assert_eq!(d.failed(), false);
+    advance_clock(0.2);
+    assert!(d.phi() > 8.0);
+    assert_eq!(d.failed(), true);
+  }
+
+  #[test]
+  fn test_normal_accrual_follows_the_window() {
+    let mut d = accrual(4, Distribution::Normal);
+    for interval in [1.0, 3.0, 1.0, 3.0] {
+      advance_clock(interval);
+      d.update();
+    }
+    assert_is_close(d.mean(), 2.0, 1e-9);
+    assert_is_close(d.variance(), 1.0, 1e-9);
+    advance_clock(4.0);
+    // two deviations past the mean, with a 2.3% chance of being that late
+    assert_is_close(d.phi(), 1.64, 1e-2);
+    assert_eq!(d.failed(), false);
+
+    // older intervals leave the window, so steady updates narrow it
+    for _ in 0..4 {
+      d.update();
+      advance_clock(1.0);
+    }
+    assert_is_close(d.mean(), 1.75, 1e-9);
+    d.update();
+    assert_is_close(d.mean(), 1.0, 1e-9);
+    advance_clock(2.0);
+    assert_eq!(d.failed(), true);
+  }
+
+  #[test]
+  fn test_exponential_accrual_phi_grows_linearly() {
+    let mut d = accrual(10, Distribution::Exponential);
+    advance_clock(2.0);
+    d.update();
+    assert_is_close(d.mean(), 1.5, 1e-9);
+    advance_clock(1.5);
+    assert_is_close(d.phi(), std::f64::consts::LOG10_E, 1e-4);
+    advance_clock(1.5);
+    assert_is_close(d.phi(), 0.86859, 1e-4);
+    advance_clock(25.0);
+    assert_eq!(d.failed(), true);
+  }
+
+  #[test]
+  fn test_timeout_fails_after_the_timeout() {
+    let mut d = TimeoutDetector::new(3.0);
+    advance_clock(2.0);
+    d.update();
+    advance_clock(1.5);
+    assert_is_close(d.phi(), 0.5, 1e-9);
+    assert_eq!(d.failed(), false);
+    advance_clock(1.6);
+    assert_eq!(d.failed(), true);
+  }
+
+  #[test]
+  fn test_strategies_create_their_detectors() {
+    fn custom() -> Box<dyn Detector> { Box::new(TimeoutDetector::new(0.5)) }
+    let strategies = [
+      Strategy::default(),
+      Strategy::Accrual {
+        threshold: 8.0, interval: 1.0, window: 100,
+        distribution: Distribution::Normal, min_standard_deviation: 0.1,
+      },
+      Strategy::Timeout { timeout: 5.0 },
+      Strategy::Custom(custom),
+    ];
+    let detectors: Vec<Box<dyn Detector>> = strategies.iter().map(|s| s.detector()).collect();
+    advance_clock(2.0);
+    let failed: Vec<bool> = detectors.iter().map(|d| d.failed()).collect();
+    assert_eq!(failed, [false, true, false, true]);
+  }
+
   #[test]
   fn test_update_interval_consistency_affects_variance() {
     let mut d = FailureDetector::default();
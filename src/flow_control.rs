@@ -0,0 +1,108 @@
+use std::net::SocketAddr;
+use fxhash::FxHashMap;
+
+// Additive increase, multiplicative decrease of the number of updates sent to
+// each peer per message, adapted from the flow control in "Efficient
+// Reconciliation and Flow Control for Anti-Entropy Protocols".
+//
+// Peers report the backlog of updates they had to leave out of their last
+// message. A backlog means the exchange with that peer is saturated, so we cut
+// back what we send it; otherwise we slowly allow more again.
+pub struct FlowControl {
+  min: f64,
+  max: f64,
+  increase: f64,
+  decrease: f64,
+  rates: FxHashMap<SocketAddr, f64>,
+}
+
+impl FlowControl {
+  pub fn new(min: usize, max: usize, increase: f64, decrease: f64) -> Self {
+    Self {
+      min: min as f64,
+      max: max as f64,
+      increase,
+      decrease,
+      rates: FxHashMap::default(),
+    }
+  }
+
+  fn rate(&self, peer: &SocketAddr) -> f64 {
+    *self.rates.get(peer).unwrap_or(&self.max)
+  }
+
+  // Maximum number of updates to send to the peer in a message.
+  pub fn limit(&self, peer: &SocketAddr) -> usize {
+    self.rate(peer) as usize
+  }
+
+  pub fn update(&mut self, peer: SocketAddr, backlog: u64) {
+    let rate = self.rate(&peer);
+    let rate = if backlog > 0 {
+      f64::max(self.min, rate * self.decrease)
+    } else {
+      f64::min(self.max, rate + self.increase)
+    };
+    self.rates.insert(peer, rate);
+  }
+
+  pub fn retain(&mut self, mut keep: impl FnMut(&SocketAddr) -> bool) {
+    self.rates.retain(|peer, _| keep(peer));
+  }
+}
+
+impl Default for FlowControl {
+  fn default() -> Self { Self::new(4, 256, 4.0, 0.5) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::{addr, addr_from};
+
+  #[test]
+  fn test_starts_at_max() {
+    let flow = FlowControl::new(2, 100, 1.0, 0.5);
+    assert_eq!(flow.limit(&addr()), 100);
+  }
+
+  #[test]
+  fn test_backlog_decreases_multiplicatively() {
+    let mut flow = FlowControl::new(2, 100, 1.0, 0.5);
+    flow.update(addr(), 10);
+    assert_eq!(flow.limit(&addr()), 50);
+    flow.update(addr(), 1);
+    assert_eq!(flow.limit(&addr()), 25);
+
+    // down to the minimum
+    for _ in 0..10 { flow.update(addr(), 1); }
+    assert_eq!(flow.limit(&addr()), 2);
+
+    // other peers are not affected
+    assert_eq!(flow.limit(&addr_from("127.1.1.12:3322")), 100);
+  }
+
+  #[test]
+  fn test_no_backlog_increases_additively() {
+    let mut flow = FlowControl::new(2, 100, 3.0, 0.5);
+    flow.update(addr(), 10);
+    flow.update(addr(), 0);
+    assert_eq!(flow.limit(&addr()), 53);
+    flow.update(addr(), 0);
+    assert_eq!(flow.limit(&addr()), 56);
+
+    // up to the maximum
+    for _ in 0..100 { flow.update(addr(), 0); }
+    assert_eq!(flow.limit(&addr()), 100);
+  }
+
+  #[test]
+  fn test_retain() {
+    let mut flow = FlowControl::new(2, 100, 1.0, 0.5);
+    flow.update(addr(), 10);
+    flow.update(addr_from("127.1.1.12:3322"), 10);
+    flow.retain(|&peer| peer == addr());
+    assert_eq!(flow.rates.len(), 1);
+    assert_eq!(flow.limit(&addr()), 50);
+  }
+}
@@ -1,59 +1,403 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use crate::node::{Node, SelfNode, PeerNode, Diff, Digest};
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::node::{Node, SelfNode, PeerNode, Digest, DISCARD_AFTER};
 use crate::peers::Peers;
+use crate::message::{Message, NodeDiff};
+use crate::event::Event;
+use crate::scuttle::{self, Order};
+use crate::flow_control::FlowControl;
+use crate::failure_detector::Strategy;
+use crate::probe::{self, Prober};
+use crate::suspicion::{self, incarnation, suspect_key, suspicion_of};
+use crate::codec;
+use crate::keys::{INCARNATION_KEY, LEADER_KEY, LEFT_KEY, TERM_KEY};
+use crate::value::Value;
+use crate::utils::{self, Instant, Rng, rand};
+
+pub type Outbound = (SocketAddr, Message);
+
+#[derive(Clone, Debug)]
+pub struct Config {
+  pub interval: Duration,
+  // maximum encoded size of a message, so it fits in a single datagram
+  pub mtu: usize,
+  pub order: Order,
+  // bounds on the number of updates sent to a peer per message, adapted by flow control
+  pub min_updates: usize,
+  pub max_updates: usize,
+  // how long tombstones for deleted keys are kept, to be sure all peers have seen them
+  // * Note: should be at least `DISCARD_AFTER` (the default), how long inactive
+  //   peers are kept, or a peer returning after being inactive that long could
+  //   gossip a deleted value back.
+  pub tombstone_grace: Duration,
+  pub detector: Strategy,
+  // probe peers directly, and indirectly through others, to detect failures sooner
+  pub probe: Option<probe::Config>,
+  // suspect failed peers, gossiping it for them to refute, before marking them inactive
+  pub suspicion: Option<suspicion::Config>,
+  // how long peers that left are kept, much shorter than for failed ones
+  pub left_grace: Duration,
+}
 
-struct Gossip {
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      interval: Duration::from_secs(1),
+      mtu: 1400,
+      order: Order::Depth,
+      min_updates: 4,
+      max_updates: 256,
+      tombstone_grace: Duration::from_secs_f64(DISCARD_AFTER),
+      detector: Strategy::default(),
+      probe: None,
+      suspicion: None,
+      left_grace: Duration::from_secs(300),
+    }
+  }
+}
+
+pub struct Gossip {
   name: String,
   node: SelfNode,
   peers: Peers,
+  config: Config,
+  flow: FlowControl,
+  prober: Prober,
+  rng: Rng,
+  next_round: Option<Instant>,
+  events: Vec<Event>,
+  // suspected peers, with when and at which of their incarnations
+  suspicions: FxHashMap<String, (Instant, i64)>,
+  // peers removed after leaving, with their generation and when, so other
+  // peers' digests do not bring them back before they have removed them too
+  removed: FxHashMap<String, (u64, Instant)>,
 }
 
-type NodeDiff = (Digest, Vec<Diff>, Option<SocketAddr>);
-
 impl Gossip {
-  fn new(cluster: &str, node: &str, address: SocketAddr, roots: Vec<SocketAddr>) -> Self {
+  pub fn new(cluster: &str, node: &str, address: SocketAddr, roots: Vec<SocketAddr>) -> Self {
+    Self::with_config(cluster, node, address, roots, Config::default())
+  }
+
+  pub fn with_config(
+    cluster: &str, node: &str, address: SocketAddr, roots: Vec<SocketAddr>, config: Config
+  ) -> Self {
     Gossip {
       name: cluster.to_string(),
       node: SelfNode::new(node.to_string(), address),
-      peers: Peers::new(roots)
+      peers: Peers::new(roots),
+      flow: FlowControl::new(config.min_updates, config.max_updates, 4.0, 0.5),
+      prober: Prober::new(cluster, config.probe.clone()),
+      config,
+      rng: utils::rng(None),
+      next_round: None,
+      events: Vec::new(),
+      suspicions: FxHashMap::default(),
+      removed: FxHashMap::default(),
+    }
+  }
+
+  pub fn name(&self) -> &str { self.name.as_str() }
+  pub fn node(&self) -> &SelfNode { &self.node }
+  pub fn node_mut(&mut self) -> &mut SelfNode { &mut self.node }
+  pub fn peers(&self) -> &Peers { &self.peers }
+
+  // The Raft leader claimed by a majority of the nodes, this one and the
+  // active peers, that publish a leader for the highest term published.
+  pub fn leader(&self) -> Option<String> {
+    let actives = self.peers.actives();
+    let claims: Vec<(i64, &str)> = std::iter::once(&self.node as &dyn Node)
+      .chain(actives.values().map(|n| *n as &dyn Node))
+      .filter_map(|n| match (n.get(TERM_KEY), n.get(LEADER_KEY)) {
+        (Some(Value::Integer(term)), Some(Value::String(leader))) => { Some((*term, leader.as_str())) }
+        _ => { None }
+      })
+      .collect();
+    let term = claims.iter().map(|(t, _)| *t).max()?;
+    let claims: Vec<&str> = claims.into_iter().filter(|(t, _)| *t == term).map(|(_, l)| l).collect();
+    let mut counts: FxHashMap<&str, usize> = FxHashMap::default();
+    for leader in claims.iter() { *counts.entry(leader).or_default() += 1; }
+    return counts.into_iter()
+      .find(|(_, count)| count * 2 > claims.len())
+      .map(|(leader, _)| leader.to_string());
+  }
+
+  // Mark this node as having left the cluster, for peers to flag it as left
+  // and soon discard it, without waiting for it to fail.
+  // * Note: keep ticking for a few rounds after, so peers hear of it.
+  pub fn leave(&mut self) {
+    self.node.set_reserved(LEFT_KEY, Value::Boolean(true));
+  }
+
+  // Take the events that have happened since the last call.
+  pub fn events(&mut self) -> Vec<Event> {
+    std::mem::take(&mut self.events)
+  }
+
+  // Start a gossip round if one is due, returning the digest messages to send.
+  // * Note: the application should call this regularly, at least as often as
+  //   the configured interval.
+  pub fn tick(&mut self, now: Instant) -> Vec<Outbound> {
+    let (mut outbound, failed) = self.prober.tick(now, &self.peers, &mut self.rng);
+    if let Some(identifier) = failed { self.fail(now, identifier); }
+    if let Some(next) = self.next_round {
+      if now < next { return outbound; }
+    }
+    self.next_round = Some(now + self.config.interval);
+
+    self.prune(now);
+
+    self.node.expire();
+    let grace = self.config.tombstone_grace.as_secs_f64();
+    self.node.collect(grace);
+    for n in self.peers.iter_mut() { n.collect(grace); }
+
+    let addresses: FxHashSet<SocketAddr> = self.peers.iter().map(|n| *n.address()).collect();
+    self.flow.retain(|address| addresses.contains(address));
+
+    let digest = self.digest();
+    let address = *self.node.address();
+    outbound.extend(
+      self.peers.targets(&mut self.rng).into_iter()
+        .filter(|&target| target != address)
+        .map(|target| (target, Message::Syn { cluster: self.name.clone(), digest: digest.clone() }))
+    );
+    return outbound;
+  }
+
+  // Process a message received from another node, returning any replies to send.
+  // * Note: messages for a different cluster are ignored.
+  pub fn handle(&mut self, now: Instant, from: SocketAddr, message: Message) -> Vec<Outbound> {
+    if message.cluster() != self.name { return Vec::new(); }
+
+    match message {
+      Message::Syn { digest, .. } => {
+        let (requests, diffs) = self.process_digest(digest);
+        if requests.is_empty() && diffs.is_empty() { return Vec::new(); }
+        let cluster = self.name.clone();
+        let empty = Message::Ack { cluster: cluster.clone(), requests: requests.clone(), diffs: vec![], backlog: 0 };
+        let (diffs, backlog) = self.pack(from, &empty, diffs);
+        if requests.is_empty() && diffs.is_empty() { return Vec::new(); }
+        return vec![(from, Message::Ack { cluster, requests, diffs, backlog })];
+      }
+      Message::Ack { requests, diffs, backlog, .. } => {
+        self.flow.update(from, backlog);
+        self.process_diffs(diffs);
+        let diffs = self.process_requests(requests);
+        if diffs.is_empty() { return Vec::new(); }
+        let cluster = self.name.clone();
+        let empty = Message::Ack2 { cluster: cluster.clone(), diffs: vec![], backlog: 0 };
+        let (diffs, backlog) = self.pack(from, &empty, diffs);
+        if diffs.is_empty() { return Vec::new(); }
+        return vec![(from, Message::Ack2 { cluster, diffs, backlog })];
+      }
+      Message::Ack2 { diffs, backlog, .. } => {
+        self.flow.update(from, backlog);
+        self.process_diffs(diffs);
+        return Vec::new();
+      }
+      Message::Ping { .. } | Message::PingReq { .. } | Message::PingAck { .. } => {
+        return self.prober.handle(now, from, message);
+      }
+    }
+  }
+
+  // Suspect a peer that failed its probe, or mark it inactive without suspicion.
+  fn fail(&mut self, now: Instant, identifier: String) {
+    if self.config.suspicion.is_some() {
+      if !self.suspicions.contains_key(&identifier) { self.suspect(now, identifier, true); }
+      return;
+    }
+    if let Some(n) = self.peers.get_mut(identifier.as_str()) {
+      if !n.active() { return; }
+      n.mark_inactive();
+      self.events.push(Event::Inactive(identifier));
     }
   }
 
+  // Suspect the active peer, gossiping the suspicion if it is our own.
+  fn suspect(&mut self, now: Instant, identifier: String, own: bool) {
+    let Some(n) = self.peers.get_mut(identifier.as_str()) else { return; };
+    if !n.active() { return; }
+    n.set_suspected(true);
+    let incarnation = incarnation(n);
+    if own { self.node.set_reserved(suspect_key(&identifier).as_str(), Value::Integer(incarnation)); }
+    self.suspicions.insert(identifier.clone(), (now, incarnation));
+    self.events.push(Event::Suspected(identifier));
+  }
+
+  fn unsuspect(&mut self, identifier: &str) {
+    self.suspicions.remove(identifier);
+    self.node.delete_reserved(suspect_key(identifier).as_str());
+    if let Some(n) = self.peers.get_mut(identifier) { n.set_suspected(false); }
+  }
+
+  // Refute suspicions of ourself, suspect peers our detector or others
+  // suspect, and mark those suspected for too long inactive.
+  fn update_suspicions(&mut self, now: Instant, config: &suspicion::Config) {
+    let identifier = self.node.identifier().to_string();
+    let suspected = self.peers.iter().filter_map(|n| suspicion_of(n, &identifier)).max();
+    if let Some(suspected) = suspected.filter(|s| *s >= incarnation(&self.node)) {
+      self.node.set_reserved(INCARNATION_KEY, Value::Integer(suspected + 1));
+    }
+
+    let mut actives: Vec<String> = self.peers.actives().into_keys().map(String::from).collect();
+    actives.sort();
+    for identifier in actives.iter() {
+      let n = self.peers.get(identifier.as_str()).unwrap();
+      let current = incarnation(n);
+      // independent suspicions of the current incarnation, from other peers
+      let confirmations = self.peers.iter()
+        .filter(|p| suspicion_of(*p, identifier).is_some_and(|i| i >= current))
+        .count();
+      match self.suspicions.get(identifier).copied() {
+        None if n.failed() => { self.suspect(now, identifier.clone(), true); }
+        None if confirmations > 0 => { self.suspect(now, identifier.clone(), false); }
+        None => {}
+        Some((_, suspected)) if current > suspected => {
+          self.unsuspect(identifier);
+          self.events.push(Event::Refuted(identifier.clone()));
+        }
+        Some((since, _)) if now >= since + config.timeout(confirmations) => {
+          self.unsuspect(identifier);
+          self.peers.get_mut(identifier.as_str()).unwrap().mark_inactive();
+          self.events.push(Event::Inactive(identifier.clone()));
+        }
+        Some(_) => {}
+      }
+    }
+
+    // forget suspicions of peers that have since become inactive
+    let forgotten: Vec<String> = self.suspicions.keys().filter(|i| !actives.contains(i)).cloned().collect();
+    for identifier in forgotten { self.unsuspect(&identifier); }
+  }
+
+  fn prune(&mut self, now: Instant) {
+    if let Some(config) = self.config.suspicion.clone() { self.update_suspicions(now, &config); }
+
+    let actives: Vec<String> = self.peers.actives().into_keys().map(String::from).collect();
+    let known: Vec<String> = self.peers.iter().map(|n| n.identifier().to_string()).collect();
+
+    self.peers.prune();
+    let grace = self.config.left_grace.as_secs_f64();
+    let departed: Vec<(String, u64)> = self.peers.iter()
+      .filter(|n| n.left() && n.inactive_for().is_some_and(|t| t >= grace))
+      .map(|n| (n.identifier().to_string(), n.generation()))
+      .collect();
+    self.removed.retain(|_, (_, at)| now.duration_since(*at) < self.config.left_grace);
+    for (identifier, generation) in departed {
+      self.peers.remove(identifier.as_str());
+      self.removed.insert(identifier, (generation, now));
+    }
+
+    for identifier in actives {
+      if self.peers.get(identifier.as_str()).is_some_and(|n| !n.active()) {
+        self.events.push(Event::Inactive(identifier));
+      }
+    }
+    for identifier in known {
+      if self.peers.get(identifier.as_str()).is_none() {
+        self.events.push(Event::Pruned(identifier));
+      }
+    }
+  }
+
+  fn changed(&mut self, identifier: &str, keys: Vec<String>) {
+    let node = self.peers.get(identifier).unwrap();
+    for key in keys {
+      let value = node.get(key.as_str()).cloned();
+      self.events.push(Event::Changed { node: identifier.to_string(), key, value });
+    }
+  }
+
+  // Fit as many diffs as possible into the space the mtu leaves in the message,
+  // within the flow control limit for the peer, returning them with the backlog.
+  fn pack(&self, to: SocketAddr, empty: &Message, diffs: Vec<NodeDiff>) -> (Vec<NodeDiff>, u64) {
+    // the empty message already includes one byte each for the diffs count and backlog
+    let overhead = codec::encode(empty).len() - 2
+      + codec::varint_len(diffs.len() as u64)
+      + codec::varint_len(u32::MAX as u64);
+    let budget = self.config.mtu.saturating_sub(overhead);
+    let (diffs, backlog) = scuttle::pack(diffs, budget, self.flow.limit(&to), self.config.order);
+    return (diffs, backlog as u64);
+  }
+
+  // Our digest and as many of our peers' as fit in a message, a different
+  // random selection of peers each round when they do not all fit.
+  fn digest(&mut self) -> Vec<Digest> {
+    let own = self.node.digest();
+    let empty = Message::Syn { cluster: self.name.clone(), digest: vec![] };
+    // the empty message already includes one byte for the digest count
+    let overhead = codec::encode(&empty).len() - 1
+      + codec::varint_len(u32::MAX as u64)
+      + codec::digest_len(&own);
+    let budget = self.config.mtu.saturating_sub(overhead);
+
+    let mut peers = self.peers.digest();
+    if peers.iter().map(codec::digest_len).sum::<usize>() > budget {
+      rand::shuffle(&mut self.rng, &mut peers, usize::MAX);
+      let mut remaining = budget;
+      peers.retain(|d| {
+        let length = codec::digest_len(d);
+        if length > remaining { return false; }
+        remaining -= length;
+        return true;
+      });
+    }
+    peers.push(own);
+    return peers;
+  }
+
+  // Whether a digest claims a newer version of ourself, from a previous run.
+  fn newer_self(&self, generation: u64, sequence: u64) -> bool {
+    let n = &self.node;
+    return generation > n.generation() ||
+      (generation == n.generation() && sequence > n.sequence());
+  }
+
   fn process_digest(&mut self, digest: Vec<Digest>) -> (Vec<Digest>, Vec<NodeDiff>) {
     let mut requests: Vec<Digest> = Vec::new();
     let mut diffs: Vec<NodeDiff> = Vec::new();
-    let mut actives = self.peers.actives();
     let mut seen_self = false;
 
-    for (identifier, sequence) in digest {
-      if self.node.identifier() == identifier {
-        seen_self = true;
-        let n = &self.node;
-        let node_sequence = self.node.sequence();
-        if n.sequence() < sequence {
-            // we received a digest claiming to have a newer version of ourself. this should not happen.
-            // console.error(`received digest for ourself with a higher sequence (${identifier} @ ${sequence} > ${node.sequence})`)
-            // @note: should we shutdown? something else? jump sequence and send update with current state?
-        } else if node_sequence > sequence {
-          diffs.push((n.digest(), n.diff(sequence), None));
-        }
-        continue;
+    for (identifier, generation, sequence) in digest.iter().cloned() {
+      if self.node.identifier() != identifier { continue; }
+      seen_self = true;
+      if self.newer_self(generation, sequence) {
+        // we have restarted and a peer still has our previous run, so move
+        // past it to a newer generation that replaces it
+        self.node.regenerate(generation);
       }
+      let n = &self.node;
+      if generation != n.generation() {
+        diffs.push((n.digest(), n.diff(0), None));
+      } else if n.sequence() > sequence {
+        diffs.push((n.digest(), n.diff(sequence), None));
+      }
+    }
+
+    let mut actives = self.peers.actives();
+    for (identifier, generation, sequence) in digest {
+      if self.node.identifier() == identifier { continue; }
 
       match self.peers.get(identifier.as_str()) {
         Some(n) => {
           actives.remove(n.identifier());
-          let node_sequence = n.sequence();
-          if node_sequence < sequence {
-            requests.push((identifier, node_sequence));
-          } else if node_sequence > sequence {
+          if generation > n.generation() ||
+            (generation == n.generation() && sequence > n.sequence()) {
+            requests.push(n.digest());
+          } else if generation < n.generation() {
+            diffs.push((n.digest(), n.diff(0), None));
+          } else if n.sequence() > sequence {
             diffs.push((n.digest(), n.diff(sequence), None));
           }
         }
+        None if self.was_removed(&identifier, generation) => {}
         None => {
           // unknown node, so request all info on it.
-          requests.push((identifier, 0));
+          requests.push((identifier, 0, 0));
         }
       }
     }
@@ -72,27 +416,52 @@ impl Gossip {
     return (requests, diffs);
   }
 
+  // Whether the peer left and was removed, in this generation or a later one.
+  fn was_removed(&self, identifier: &str, generation: u64) -> bool {
+    self.removed.get(identifier).is_some_and(|(g, _)| generation <= *g)
+  }
+
   fn process_diffs(&mut self, diffs: Vec<NodeDiff>) {
-    for ((identifier, sequence), updates, address) in diffs {
+    for ((identifier, generation, sequence), updates, address) in diffs {
       if self.node.identifier() == identifier {
-        // we received an update for ourself. this should not happen.
-        // console.error(`received diffs to update ourself (${identifier} @ ${sequence})`)
-        // @note: should we shutdown? something else?
+        // we received an update for a previous run of ourself, so move past it.
+        if self.newer_self(generation, sequence) { self.node.regenerate(generation); }
         continue;
       }
 
+      let known = self.peers.get(identifier.as_str()).is_some();
+      if !known && self.was_removed(&identifier, generation) { continue; }
+
       match self.peers.get_mut(identifier.as_str()) {
-        Some(n) => { n.apply(sequence, updates); }
+        Some(n) => {
+          let (active, previous) = (n.active(), n.generation());
+          let changed = n.apply(generation, sequence, updates);
+          let left = departed(n, &changed);
+          if n.generation() > previous && previous != 0 {
+            self.events.push(Event::Restarted(identifier.clone()));
+          }
+          if !active && n.active() {
+            self.events.push(Event::Active(identifier.clone()));
+          }
+          if left { self.events.push(Event::Left(identifier.clone())); }
+          self.changed(identifier.as_str(), changed);
+        }
         None => {
           match address {
             Some(a) => {
-              let mut new_node = PeerNode::new(identifier, a);
-              new_node.apply(sequence, updates);
+              let mut new_node = PeerNode::with_detector(identifier.clone(), a, self.config.detector.clone());
+              let changed = new_node.apply(generation, sequence, updates);
+              let left = departed(&mut new_node, &changed);
+              let active = new_node.active();
               self.peers.add(new_node);
+              self.events.push(Event::Joined(identifier.clone()));
+              if active { self.events.push(Event::Active(identifier.clone())); }
+              if left { self.events.push(Event::Left(identifier.clone())); }
+              self.changed(identifier.as_str(), changed);
             }
             None => {
               // @todo: log unknown node with no address
-              return;
+              continue;
             }
           }
         }
@@ -103,18 +472,21 @@ impl Gossip {
   fn process_requests(&mut self, requests: Vec<Digest>) -> Vec<NodeDiff> {
     let mut diffs: Vec<NodeDiff> = Vec::new();
 
-    let mut add = |n: &dyn Node, sequence: u64| {
-      if n.sequence() > sequence {
-          let mut diff: NodeDiff = (n.digest(), n.diff(sequence), None);
-        if sequence == 0 { diff.2 = Some(*n.address()); }
+    let mut add = |n: &dyn Node, generation: u64, sequence: u64| {
+      // a different generation needs everything from the current one
+      let same = n.generation() == generation;
+      let from = if same { sequence } else { 0 };
+      if n.sequence() > from || !same {
+        let mut diff: NodeDiff = (n.digest(), n.diff(from), None);
+        if from == 0 { diff.2 = Some(*n.address()); }
         diffs.push(diff);
       }
     };
 
-    for (identifier, sequence) in requests {
-      if self.node.identifier() == identifier { add(&self.node, sequence); }
+    for (identifier, generation, sequence) in requests {
+      if self.node.identifier() == identifier { add(&self.node, generation, sequence); }
       match self.peers.get(identifier.as_str()) {
-        Some(n) => { add(n, sequence); }
+        Some(n) => { add(n, generation, sequence); }
         None => {
           // @todo: log unknown node
         }
@@ -124,3 +496,527 @@ impl Gossip {
     return diffs;
   }
 }
+
+// Whether the changes show the peer has just left, marking it inactive if so.
+fn departed(node: &mut PeerNode, changed: &[String]) -> bool {
+  if !node.left() || !changed.iter().any(|k| k == LEFT_KEY) { return false; }
+  node.mark_inactive();
+  return true;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::{addr_from, advance_clock};
+
+  fn gossip(node: &str, address: &str, roots: &str) -> Gossip {
+    gossip_with(node, address, roots, Config::default())
+  }
+
+  fn gossip_with(node: &str, address: &str, roots: &str, config: Config) -> Gossip {
+    let roots = if roots.is_empty() { vec![] } else { vec![addr_from(roots)] };
+    Gossip::with_config("cluster", node, addr_from(address), roots, config)
+  }
+
+  fn round(a: &mut Gossip, b: &mut Gossip) {
+    let from = *a.node().address();
+    let messages = a.tick(Instant::now()).into_iter().map(|m| (from, m)).collect();
+    exchange(&mut [a, b], messages);
+    advance_clock(1.0);
+  }
+
+  // a round from each of the nodes, with messages to others lost
+  fn rounds(nodes: &mut [&mut Gossip]) {
+    let mut messages = Vec::new();
+    for n in nodes.iter_mut() {
+      let from = *n.node().address();
+      messages.extend(n.tick(Instant::now()).into_iter().map(|m| (from, m)));
+    }
+    let addresses: Vec<SocketAddr> = nodes.iter().map(|n| *n.node().address()).collect();
+    messages.retain(|(_, (to, _))| addresses.contains(to));
+    exchange(nodes, messages);
+    advance_clock(1.0);
+  }
+
+  // deliver messages between nodes until there are no more replies
+  fn exchange(nodes: &mut [&mut Gossip], mut messages: Vec<(SocketAddr, Outbound)>) {
+    while let Some((from, (to, message))) = messages.pop() {
+      let node = nodes.iter_mut().find(|n| *n.node().address() == to).unwrap();
+      let address = *node.node().address();
+      for reply in node.handle(Instant::now(), from, message) {
+        messages.push((address, reply));
+      }
+    }
+  }
+
+  #[test]
+  fn test_tick_targets_roots_with_digest() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    a.node_mut().set("key", 1.into()).unwrap();
+
+    let outbound = a.tick(Instant::now());
+    assert_eq!(outbound.len(), 1);
+    assert_eq!(outbound[0].0, addr_from("127.1.1.12:3322"));
+    assert_eq!(outbound[0].1, Message::Syn {
+      cluster: "cluster".into(),
+      digest: vec![("a".into(), a.node().generation(), 1)],
+    });
+  }
+
+  #[test]
+  fn test_tick_waits_for_interval() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    assert_eq!(a.tick(Instant::now()).len(), 1);
+    assert!(a.tick(Instant::now()).is_empty());
+
+    advance_clock(0.5);
+    assert!(a.tick(Instant::now()).is_empty());
+
+    advance_clock(0.5);
+    assert_eq!(a.tick(Instant::now()).len(), 1);
+  }
+
+  #[test]
+  fn test_tick_skips_self_as_root() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.11:3322");
+    assert!(a.tick(Instant::now()).is_empty());
+  }
+
+  #[test]
+  fn test_round_exchanges_state() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    a.node_mut().set("key", "from a".into()).unwrap();
+    b.node_mut().set("key", "from b".into()).unwrap();
+    b.node_mut().set("other", 2.into()).unwrap();
+
+    let from = *a.node().address();
+    let messages = a.tick(Instant::now()).into_iter().map(|m| (from, m)).collect();
+    exchange(&mut [&mut a, &mut b], messages);
+
+    let peer = a.peers().get("b").unwrap();
+    assert_eq!(peer.sequence(), 2);
+    assert_eq!(peer.get("key"), Some(&"from b".into()));
+    assert_eq!(peer.get("other"), Some(&2.into()));
+
+    let peer = b.peers().get("a").unwrap();
+    assert_eq!(peer.sequence(), 1);
+    assert_eq!(peer.get("key"), Some(&"from a".into()));
+    assert_eq!(peer.address(), &addr_from("127.1.1.11:3322"));
+  }
+
+  #[test]
+  fn test_round_with_nothing_new_has_no_replies() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    a.node_mut().set("key", 1.into()).unwrap();
+    b.node_mut().set("key", 2.into()).unwrap();
+
+    let from = *a.node().address();
+    let messages = a.tick(Instant::now()).into_iter().map(|m| (from, m)).collect();
+    exchange(&mut [&mut a, &mut b], messages);
+
+    let syn = Message::Syn {
+      cluster: "cluster".into(),
+      digest: vec![("b".into(), b.node().generation(), 1), ("a".into(), a.node().generation(), 1)],
+    };
+    assert!(b.handle(Instant::now(), from, syn).is_empty());
+  }
+
+  #[test]
+  fn test_round_propagates_deletes() {
+    let config = Config { tombstone_grace: Duration::from_secs(10), ..Config::default() };
+    let mut a = gossip_with("a", "127.1.1.11:3322", "127.1.1.12:3322", config.clone());
+    let mut b = gossip_with("b", "127.1.1.12:3322", "", config);
+    a.node_mut().set("key", 1.into()).unwrap();
+    a.node_mut().set("other", 2.into()).unwrap();
+    round(&mut a, &mut b);
+    assert_eq!(b.peers().get("a").unwrap().get("key"), Some(&1.into()));
+
+    a.node_mut().delete("key").unwrap();
+    round(&mut a, &mut b);
+    let peer = b.peers().get("a").unwrap();
+    assert_eq!(peer.sequence(), 3);
+    assert!(peer.get("key").is_none());
+    assert_eq!(peer.diff(0).len(), 2);
+
+    // tombstones are collected after the grace period
+    advance_clock(10.0);
+    round(&mut a, &mut b);
+    round(&mut b, &mut a);
+    assert_eq!(a.node().diff(0).len(), 1);
+    assert_eq!(b.peers().get("a").unwrap().diff(0).len(), 1);
+  }
+
+  #[test]
+  fn test_round_propagates_expired_values() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    a.node_mut().set_with_ttl("load", 0.5.into(), 1.5).unwrap();
+    round(&mut a, &mut b);
+    assert_eq!(b.peers().get("a").unwrap().get("load"), Some(&0.5.into()));
+
+    round(&mut a, &mut b);
+    round(&mut a, &mut b);
+    assert!(b.peers().get("a").unwrap().get("load").is_none());
+    assert_eq!(b.peers().get("a").unwrap().sequence(), 2);
+  }
+
+  #[test]
+  fn test_leader_by_majority_of_highest_term() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "127.1.1.13:3322");
+    let mut c = gossip("c", "127.1.1.13:3322", "127.1.1.11:3322");
+    assert_eq!(a.leader(), None);
+
+    let claim = |g: &mut Gossip, term: i64, leader: &str| {
+      g.node_mut().set_reserved(TERM_KEY, Value::Integer(term));
+      g.node_mut().set_reserved(LEADER_KEY, leader.into());
+    };
+    claim(&mut a, 2, "a");
+    claim(&mut b, 2, "a");
+    claim(&mut c, 1, "c");
+    assert_eq!(a.leader(), Some("a".into()));
+    for _ in 0..3 { rounds(&mut [&mut a, &mut b, &mut c]); }
+    assert_eq!(c.leader(), Some("a".into()));
+
+    // claims for a higher term win, and need a majority among themselves
+    claim(&mut c, 3, "c");
+    assert_eq!(c.leader(), Some("c".into()));
+    claim(&mut b, 3, "b");
+    rounds(&mut [&mut b, &mut c]);
+    assert_eq!(c.leader(), None);
+    assert_eq!(a.leader(), Some("a".into()));
+  }
+
+  // tick the nodes every 0.1s for the seconds, with a heartbeat each round,
+  // losing messages over the cut links
+  fn run(nodes: &mut [&mut Gossip], seconds: f64, cut: &[(&str, &str)]) -> Vec<Message> {
+    let cut: Vec<(SocketAddr, SocketAddr)> = cut.iter().map(|(a, b)| (addr_from(a), addr_from(b))).collect();
+    let addresses: Vec<SocketAddr> = nodes.iter().map(|n| *n.node().address()).collect();
+    let connected = |from: SocketAddr, to: SocketAddr| {
+      addresses.contains(&to) &&
+        !cut.iter().any(|&(a, b)| (a, b) == (from, to) || (b, a) == (from, to))
+    };
+    let mut sent = Vec::new();
+    for _ in 0..(seconds * 10.0).round() as usize {
+      let mut messages = Vec::new();
+      for n in nodes.iter_mut() {
+        let from = *n.node().address();
+        let outbound = n.tick(Instant::now());
+        if outbound.iter().any(|(_, m)| m.kind() == "syn") {
+          let heartbeat = n.node().sequence() as i64;
+          n.node_mut().set("heartbeat", heartbeat.into()).unwrap();
+        }
+        messages.extend(outbound.into_iter().map(|m| (from, m)));
+      }
+      while let Some((from, (to, message))) = messages.pop() {
+        sent.push(message.clone());
+        if !connected(from, to) { continue; }
+        let node = nodes.iter_mut().find(|n| *n.node().address() == to).unwrap();
+        messages.extend(node.handle(Instant::now(), from, message).into_iter().map(|m| (to, m)));
+      }
+      advance_clock(0.1);
+    }
+    return sent;
+  }
+
+  #[test]
+  fn test_probes_go_through_peers_and_fail_unreachable_ones() {
+    let config = Config { probe: Some(probe::Config::default()), ..Config::default() };
+    let mut a = gossip_with("a", "127.1.1.11:3322", "127.1.1.12:3322", config);
+    let mut b = gossip("b", "127.1.1.12:3322", "127.1.1.13:3322");
+    let mut c = gossip("c", "127.1.1.13:3322", "127.1.1.11:3322");
+    let sent = run(&mut [&mut a, &mut b, &mut c], 5.0, &[]);
+    assert!(sent.iter().any(|m| m.kind() == "ping-ack"));
+    assert!(!sent.iter().any(|m| m.kind() == "ping-req"));
+    assert_eq!(a.peers().actives().len(), 2);
+
+    // a bad link to c is probed around, through b
+    let sent = run(&mut [&mut a, &mut b, &mut c], 5.0, &[("127.1.1.11:3322", "127.1.1.13:3322")]);
+    assert!(sent.iter().any(|m| m.kind() == "ping-req"));
+    assert_eq!(a.peers().actives().len(), 2);
+    assert!(!a.events().contains(&Event::Inactive("c".into())));
+
+    // but once c is down, it fails a probe well before the detector notices
+    run(&mut [&mut a, &mut b], 3.0, &[]);
+    assert!(a.events().contains(&Event::Inactive("c".into())));
+    assert!(a.peers().get("c").unwrap().phi().is_none());
+    assert!(b.peers().get("c").unwrap().active());
+  }
+
+  #[test]
+  fn test_suspected_peers_refute_or_become_inactive() {
+    let config = Config {
+      detector: Strategy::Timeout { timeout: 2.5 },
+      suspicion: Some(suspicion::Config {
+        min_timeout: Duration::from_secs(4),
+        max_timeout: Duration::from_secs(16),
+        confirmations: 1,
+      }),
+      ..Config::default()
+    };
+    let mut a = gossip_with("a", "127.1.1.11:3322", "127.1.1.12:3322", config.clone());
+    let mut b = gossip_with("b", "127.1.1.12:3322", "127.1.1.13:3322", config.clone());
+    let mut c = gossip_with("c", "127.1.1.13:3322", "127.1.1.11:3322", config);
+    run(&mut [&mut a, &mut b, &mut c], 5.0, &[]);
+    a.events();
+
+    // c pausing is suspected, rather than marked inactive
+    run(&mut [&mut a, &mut b], 3.0, &[]);
+    assert!(a.events().contains(&Event::Suspected("c".into())));
+    assert!(a.node().get(&suspect_key("c")).is_some());
+    assert!(a.peers().get("c").unwrap().active());
+
+    // until it hears of it, and refutes it with a new incarnation
+    run(&mut [&mut a, &mut b, &mut c], 3.0, &[]);
+    assert_eq!(c.node().get(INCARNATION_KEY), Some(&1.into()));
+    assert!(a.events().contains(&Event::Refuted("c".into())));
+    assert!(a.node().get(&suspect_key("c")).is_none());
+    assert!(a.peers().get("c").unwrap().active());
+
+    // but once down for the suspicion timeout, with b confirming, it is inactive
+    run(&mut [&mut a, &mut b], 5.0, &[]);
+    assert!(a.peers().get("c").unwrap().active());
+    run(&mut [&mut a, &mut b], 2.0, &[]);
+    let events = a.events();
+    assert!(events.contains(&Event::Suspected("c".into())));
+    assert!(events.contains(&Event::Inactive("c".into())));
+    assert!(a.node().get(&suspect_key("c")).is_none());
+  }
+
+  #[test]
+  fn test_left_peers_are_flagged_and_soon_discarded() {
+    let config = Config { left_grace: Duration::from_secs(10), ..Config::default() };
+    let mut a = gossip_with("a", "127.1.1.11:3322", "127.1.1.12:3322", config.clone());
+    let mut b = gossip_with("b", "127.1.1.12:3322", "127.1.1.13:3322", config.clone());
+    let mut c = gossip_with("c", "127.1.1.13:3322", "127.1.1.11:3322", config);
+    run(&mut [&mut a, &mut b, &mut c], 5.0, &[]);
+    a.events();
+
+    // peers flag it as left, even as it keeps sending updates
+    c.leave();
+    run(&mut [&mut a, &mut b, &mut c], 3.0, &[]);
+    let events = a.events();
+    assert!(events.contains(&Event::Left("c".into())));
+    assert!(!events.contains(&Event::Inactive("c".into())));
+    assert!(a.peers().get("c").unwrap().left());
+    assert!(!a.peers().get("c").unwrap().active());
+    assert!(!b.peers().actives().contains_key("c"));
+
+    // and discard it after the shorter grace period
+    run(&mut [&mut a, &mut b], 6.0, &[]);
+    assert!(a.peers().get("c").is_some());
+    run(&mut [&mut a, &mut b], 4.0, &[]);
+    assert!(a.peers().get("c").is_none());
+    assert!(a.events().contains(&Event::Pruned("c".into())));
+    assert_eq!(a.peers().actives().len(), 1);
+
+    // nor bring it back from c's own gossip, or another peer's
+    run(&mut [&mut a, &mut b, &mut c], 3.0, &[]);
+    assert!(a.peers().get("c").is_none());
+    assert!(b.peers().get("c").is_none());
+    assert!(!a.events().contains(&Event::Joined("c".into())));
+  }
+
+  #[test]
+  fn test_restarted_node_replaces_previous_run() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    for key in ["k1", "k2", "k3"] { a.node_mut().set(key, 1.into()).unwrap(); }
+    round(&mut a, &mut b);
+    let generation = a.node().generation();
+    assert_eq!(b.peers().get("a").unwrap().digest(), ("a".into(), generation, 3));
+
+    // restarted within the same second, so with the same generation
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    a.node = SelfNode::with_generation("a".into(), *a.node().address(), generation);
+    a.node_mut().set("k4", 2.into()).unwrap();
+
+    // seeing the previous run with a higher sequence moves it to a newer generation
+    round(&mut b, &mut a);
+    assert!(a.node().generation() > generation);
+    let peer = b.peers().get("a").unwrap();
+    assert_eq!(peer.digest(), ("a".into(), a.node().generation(), 1));
+    assert!(peer.get("k1").is_none());
+    assert_eq!(peer.get("k4"), Some(&2.into()));
+  }
+
+  #[test]
+  fn test_requests_from_another_generation_get_everything() {
+    let mut a = gossip("a", "127.1.1.11:3322", "");
+    a.node_mut().set("k1", 1.into()).unwrap();
+    a.node_mut().set("k2", 2.into()).unwrap();
+    let generation = a.node().generation();
+    let from = addr_from("127.1.1.12:3322");
+
+    let ack = |requests| Message::Ack { cluster: "cluster".into(), requests, diffs: vec![], backlog: 0 };
+    let updates = |mut replies: Vec<Outbound>| match replies.pop() {
+      Some((_, Message::Ack2 { diffs, .. })) => diffs[0].1.len(),
+      _ => 0,
+    };
+    assert_eq!(updates(a.handle(Instant::now(), from, ack(vec![("a".into(), generation, 1)]))), 1);
+    assert_eq!(updates(a.handle(Instant::now(), from, ack(vec![("a".into(), generation - 1, 1)]))), 2);
+    assert_eq!(updates(a.handle(Instant::now(), from, ack(vec![("a".into(), generation, 2)]))), 0);
+  }
+
+  #[test]
+  fn test_events() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    a.node_mut().set("key", 1.into()).unwrap();
+    round(&mut a, &mut b);
+    assert_eq!(b.events(), [
+      Event::Joined("a".into()),
+      Event::Active("a".into()),
+      Event::Changed { node: "a".into(), key: "key".into(), value: Some(1.into()) },
+    ]);
+    assert!(b.events().is_empty());
+
+    a.node_mut().delete("key").unwrap();
+    round(&mut a, &mut b);
+    assert_eq!(b.events(), [
+      Event::Changed { node: "a".into(), key: "key".into(), value: None },
+    ]);
+
+    // a stops gossiping, so b's detector marks it inactive and later prunes it
+    advance_clock(100.0);
+    b.tick(Instant::now());
+    assert_eq!(b.events(), [Event::Inactive("a".into())]);
+    advance_clock(100_000.0);
+    b.tick(Instant::now());
+    assert_eq!(b.events(), [Event::Pruned("a".into())]);
+  }
+
+  #[test]
+  fn test_restart_events() {
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    a.node = SelfNode::with_generation("a".into(), *a.node().address(), 1);
+    a.node_mut().set("key", 1.into()).unwrap();
+    round(&mut a, &mut b);
+    b.events();
+
+    let mut a = gossip("a", "127.1.1.11:3322", "127.1.1.12:3322");
+    a.node_mut().set("other", 2.into()).unwrap();
+    round(&mut a, &mut b);
+    assert_eq!(b.events(), [
+      Event::Restarted("a".into()),
+      Event::Changed { node: "a".into(), key: "key".into(), value: None },
+      Event::Changed { node: "a".into(), key: "other".into(), value: Some(2.into()) },
+    ]);
+  }
+
+  #[test]
+  fn test_rounds_respect_mtu() {
+    for order in [Order::Depth, Order::Breadth] {
+      let config = Config { mtu: 120, order, ..Config::default() };
+      let mut a = gossip_with("a", "127.1.1.11:3322", "127.1.1.12:3322", config.clone());
+      let mut b = gossip_with("b", "127.1.1.12:3322", "", config);
+      for i in 0..20 {
+        a.node_mut().set(format!("key{}", i).as_str(), "some longer value".into()).unwrap();
+        b.node_mut().set(format!("key{}", i).as_str(), i.into()).unwrap();
+      }
+
+      let from = *a.node().address();
+      let (to, syn) = a.tick(Instant::now()).pop().unwrap();
+      let ack = b.handle(Instant::now(), from, syn).pop().unwrap().1;
+      assert!(codec::encode(&ack).len() <= 120);
+      let ack2 = a.handle(Instant::now(), to, ack).pop().unwrap().1;
+      assert!(codec::encode(&ack2).len() <= 120);
+      b.handle(Instant::now(), from, ack2);
+
+      // partially updated to a consistent prefix
+      let peer = b.peers().get("a").unwrap();
+      assert!(peer.sequence() < 20);
+      assert_eq!(peer.get("key0"), Some(&"some longer value".into()));
+      assert!(peer.get("key19").is_none());
+
+      // and eventually fully updated
+      for _ in 0..20 { round(&mut a, &mut b); }
+      assert_eq!(b.peers().get("a").unwrap().sequence(), 20);
+      assert_eq!(a.peers().get("b").unwrap().sequence(), 20);
+      assert_eq!(a.peers().get("b").unwrap().get("key19"), Some(&19.into()));
+    }
+  }
+
+  #[test]
+  fn test_digest_respects_mtu() {
+    let config = Config { mtu: 120, ..Config::default() };
+    let mut a = gossip_with("a", "127.1.1.11:3322", "", config);
+    let diffs = (0..20)
+      .map(|i| ((format!("peer{}", i), 1, 0), vec![], Some(addr_from(&format!("127.1.2.{}:3322", i + 1)))))
+      .collect();
+    let ack2 = Message::Ack2 { cluster: "cluster".into(), diffs, backlog: 0 };
+    a.handle(Instant::now(), addr_from("127.1.2.1:3322"), ack2);
+    assert_eq!(a.peers().len(), 20);
+
+    let syns = a.tick(Instant::now());
+    assert!(!syns.is_empty());
+    for (_, syn) in syns {
+      assert!(codec::encode(&syn).len() <= 120);
+      let Message::Syn { digest, .. } = syn else { panic!("expected a syn") };
+      assert!(digest.len() < 21);
+      assert!(digest.contains(&a.node().digest()));
+    }
+  }
+
+  #[test]
+  fn test_empty_ack_is_not_sent() {
+    let config = Config { mtu: 40, ..Config::default() };
+    let mut b = gossip_with("b", "127.1.1.12:3322", "", config);
+    b.node_mut().set("key", "a value too long to fit in the message".into()).unwrap();
+    let syn = Message::Syn { cluster: "cluster".into(), digest: vec![("b".into(), b.node().generation(), 0)] };
+    assert!(b.handle(Instant::now(), addr_from("127.1.1.11:3322"), syn).is_empty());
+  }
+
+  #[test]
+  fn test_backlog_adapts_updates_per_message() {
+    let config = Config { min_updates: 2, max_updates: 16, ..Config::default() };
+    let mut a = gossip_with("a", "127.1.1.11:3322", "127.1.1.12:3322", config.clone());
+    let mut b = gossip_with("b", "127.1.1.12:3322", "", config);
+    for i in 0..40 {
+      b.node_mut().set(format!("key{}", i).as_str(), i.into()).unwrap();
+    }
+    let from = *a.node().address();
+    let updates = |m: &Message| match m {
+      Message::Ack { diffs, backlog, .. } => (diffs.iter().map(|d| d.1.len()).sum::<usize>(), *backlog),
+      _ => panic!("expected an ack"),
+    };
+
+    let syn = a.tick(Instant::now()).pop().unwrap().1;
+    let ack = b.handle(Instant::now(), from, syn).pop().unwrap().1;
+    assert_eq!(updates(&ack), (16, 24));
+
+    // a reports a backlog of its own in its reply...
+    let ack2 = Message::Ack2 { cluster: "cluster".into(), diffs: vec![], backlog: 3 };
+    assert!(b.handle(Instant::now(), from, ack2).is_empty());
+
+    // ...so b halves what it sends to a
+    advance_clock(1.0);
+    let syn = a.tick(Instant::now()).pop().unwrap().1;
+    let ack = b.handle(Instant::now(), from, syn).pop().unwrap().1;
+    assert_eq!(updates(&ack), (8, 32));
+
+    // and increases it again once a has no backlog
+    let ack2 = Message::Ack2 { cluster: "cluster".into(), diffs: vec![], backlog: 0 };
+    b.handle(Instant::now(), from, ack2);
+    advance_clock(1.0);
+    let syn = a.tick(Instant::now()).pop().unwrap().1;
+    let ack = b.handle(Instant::now(), from, syn).pop().unwrap().1;
+    assert_eq!(updates(&ack), (12, 28));
+  }
+
+  #[test]
+  fn test_handle_ignores_other_clusters() {
+    let mut b = gossip("b", "127.1.1.12:3322", "");
+    b.node_mut().set("key", 1.into()).unwrap();
+    let from = addr_from("127.1.1.11:3322");
+
+    let syn = Message::Syn { cluster: "other".into(), digest: vec![] };
+    assert!(b.handle(Instant::now(), from, syn).is_empty());
+
+    let syn = Message::Syn { cluster: "cluster".into(), digest: vec![] };
+    assert_eq!(b.handle(Instant::now(), from, syn).len(), 1);
+  }
+}
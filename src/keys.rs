@@ -0,0 +1,39 @@
+// Gossip keys the protocols publish under, shared so the gossip layer can read
+// them without depending on the protocols that set them. They all start with
+// the reserved prefix, which application keys may not use.
+pub const RESERVED_PREFIX: &str = "_sys.";
+
+// Set by a node leaving the cluster, so peers stop expecting to hear from it.
+pub const LEFT_KEY: &str = "_sys.left";
+
+// Each node's incarnation, bumped to refute suspicions of it.
+pub const INCARNATION_KEY: &str = "_sys.incarnation";
+// A node suspecting a peer sets this prefix and the peer's identifier to the
+// incarnation it suspects, so the suspicion is gossiped cluster-wide.
+pub const SUSPECT_PREFIX: &str = "_sys.suspect.";
+
+// A node sets this to "learner" to only ever join raft as a learner, or
+// "voter" (the default) to become a voter.
+pub const DESIRED_ROLE_KEY: &str = "_sys.raft.desired_role";
+
+// Each node publishes its raft term, role and known leader under these, so
+// `Gossip::leader()` can find the leader without asking raft.
+pub const TERM_KEY: &str = "_sys.raft.term";
+pub const ROLE_KEY: &str = "_sys.raft.role";
+pub const LEADER_KEY: &str = "_sys.raft.leader";
+
+pub fn reserved(key: &str) -> bool { key.starts_with(RESERVED_PREFIX) }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_protocol_keys_are_reserved() {
+    for key in [LEFT_KEY, INCARNATION_KEY, SUSPECT_PREFIX, DESIRED_ROLE_KEY, TERM_KEY, ROLE_KEY, LEADER_KEY] {
+      assert!(reserved(key), "{}", key);
+    }
+    assert!(!reserved("sys.key"));
+    assert!(!reserved("key"));
+  }
+}
@@ -0,0 +1,15 @@
+pub mod failure_detector;
+pub mod utils;
+pub mod node;
+pub mod peers;
+pub mod gossip;
+pub mod probe;
+pub mod suspicion;
+pub mod message;
+pub mod event;
+pub mod codec;
+pub mod scuttle;
+pub mod flow_control;
+pub mod value;
+pub mod keys;
+pub mod raft;
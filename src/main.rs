@@ -1,11 +1,5 @@
-mod failure_detector;
-mod utils;
-mod node;
-mod peers;
-mod gossip;
-mod value;
-
 use indexmap::IndexMap;
+use scuttleraft::utils;
 
 
 fn main() {
@@ -39,7 +33,7 @@ fn main() {
         println!("{}", rng.rand_u64());
     }
 
-    let mut aa = (0..100).collect();
+    let mut aa: Vec<u32> = (0..100).collect();
     utils::rand::shuffle(&mut rng, &mut aa, usize::MAX);
     println!("{:?}", aa);
 
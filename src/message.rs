@@ -0,0 +1,129 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::node::{Diff, Digest};
+
+pub type NodeDiff = (Digest, Vec<Diff>, Option<SocketAddr>);
+
+// The three-way Scuttlebutt exchange:
+// * Syn: the initiator sends a digest of every node it knows about.
+// * Ack: the receiver replies with requests for anything newer in the digest,
+//   and diffs for anything the initiator is missing.
+// * Ack2: the initiator replies with diffs answering those requests.
+// The messages with diffs also report the sender's backlog, the number of
+// updates it had to leave out, which peers use for flow control.
+//
+// And the probes, when enabled:
+// * Ping: a direct probe, acked with the same sequence.
+// * PingReq: a request to ping the target, passing its ack back.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+  Syn { cluster: String, digest: Vec<Digest> },
+  Ack { cluster: String, requests: Vec<Digest>, diffs: Vec<NodeDiff>, backlog: u64 },
+  Ack2 { cluster: String, diffs: Vec<NodeDiff>, backlog: u64 },
+  Ping { cluster: String, sequence: u64 },
+  PingReq { cluster: String, sequence: u64, target: SocketAddr },
+  PingAck { cluster: String, sequence: u64 },
+}
+
+impl Message {
+  pub fn cluster(&self) -> &str {
+    match self {
+      Self::Syn { cluster, .. } => { cluster }
+      Self::Ack { cluster, .. } => { cluster }
+      Self::Ack2 { cluster, .. } => { cluster }
+      Self::Ping { cluster, .. } => { cluster }
+      Self::PingReq { cluster, .. } => { cluster }
+      Self::PingAck { cluster, .. } => { cluster }
+    }
+  }
+
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Syn { .. } => { "syn" }
+      Self::Ack { .. } => { "ack" }
+      Self::Ack2 { .. } => { "ack2" }
+      Self::Ping { .. } => { "ping" }
+      Self::PingReq { .. } => { "ping-req" }
+      Self::PingAck { .. } => { "ping-ack" }
+    }
+  }
+}
+
+fn count_updates(diffs: &[NodeDiff]) -> usize {
+  diffs.iter().map(|(_, updates, _)| updates.len()).sum()
+}
+
+impl fmt::Display for Message {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}[{}]", self.kind(), self.cluster())?;
+    match self {
+      Self::Syn { digest, .. } => {
+        write!(f, " digest={}", digest.len())
+      }
+      Self::Ack { requests, diffs, backlog, .. } => {
+        write!(
+          f, " requests={} nodes={} updates={} backlog={}",
+          requests.len(), diffs.len(), count_updates(diffs), backlog
+        )
+      }
+      Self::Ack2 { diffs, backlog, .. } => {
+        write!(f, " nodes={} updates={} backlog={}", diffs.len(), count_updates(diffs), backlog)
+      }
+      Self::Ping { sequence, .. } | Self::PingAck { sequence, .. } => {
+        write!(f, " sequence={}", sequence)
+      }
+      Self::PingReq { sequence, target, .. } => {
+        write!(f, " sequence={} target={}", sequence, target)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::addr;
+
+  #[test]
+  fn test_message_cluster_and_kind() {
+    let syn = Message::Syn { cluster: "c1".into(), digest: vec![] };
+    assert_eq!(syn.cluster(), "c1");
+    assert_eq!(syn.kind(), "syn");
+
+    let ack = Message::Ack { cluster: "c2".into(), requests: vec![], diffs: vec![], backlog: 0 };
+    assert_eq!(ack.cluster(), "c2");
+    assert_eq!(ack.kind(), "ack");
+
+    let ack2 = Message::Ack2 { cluster: "c3".into(), diffs: vec![], backlog: 0 };
+    assert_eq!(ack2.cluster(), "c3");
+    assert_eq!(ack2.kind(), "ack2");
+  }
+
+  #[test]
+  fn test_message_display() {
+    let syn = Message::Syn {
+      cluster: "c1".into(),
+      digest: vec![("a".into(), 1, 1), ("b".into(), 1, 2)],
+    };
+    assert_eq!(syn.to_string(), "syn[c1] digest=2");
+
+    let diffs: Vec<NodeDiff> = vec![
+      (("a".into(), 1, 2), vec![("k1".into(), (1.into(), 1)), ("k2".into(), (2.into(), 2))], Some(addr())),
+      (("b".into(), 1, 1), vec![("k1".into(), (3.into(), 1))], None),
+    ];
+    let ack = Message::Ack {
+      cluster: "c1".into(), requests: vec![("c".into(), 0, 0)], diffs: diffs.clone(), backlog: 0,
+    };
+    assert_eq!(ack.to_string(), "ack[c1] requests=1 nodes=2 updates=3 backlog=0");
+
+    let ack2 = Message::Ack2 { cluster: "c1".into(), diffs, backlog: 7 };
+    assert_eq!(ack2.to_string(), "ack2[c1] nodes=2 updates=3 backlog=7");
+
+    let ping_req = Message::PingReq { cluster: "c1".into(), sequence: 4, target: addr() };
+    assert_eq!(ping_req.kind(), "ping-req");
+    assert_eq!(ping_req.to_string(), "ping-req[c1] sequence=4 target=127.1.1.11:3322");
+    let ping_ack = Message::PingAck { cluster: "c1".into(), sequence: 4 };
+    assert_eq!(ping_ack.to_string(), "ping-ack[c1] sequence=4");
+  }
+}
@@ -1,17 +1,24 @@
+use std::fmt;
 use std::net::SocketAddr;
 use fxhash::FxHashMap;
 
 use crate::value::Value;
-use crate::failure_detector::FailureDetector;
-use crate::utils::Touch;
+use crate::failure_detector::{Detector, Strategy};
+use crate::keys::{self, LEFT_KEY};
+use crate::utils::{self, Touch};
+
+// Seconds an inactive peer is kept before it is discarded.
+pub const DISCARD_AFTER: f64 = 86_400.0;
 
 type SequencedValue = (Value, u64);
 pub type Diff = (String, (Value, u64));
-pub type Digest = (String, u64);
+// identifier, generation and sequence
+pub type Digest = (String, u64, u64);
 
 pub trait Node {
   fn identifier(&self) -> &str;
   fn address(&self) -> &SocketAddr;
+  fn generation(&self) -> u64;
   fn sequence(&self) -> u64;
   fn digest(&self) -> Digest;
   fn get(&self, key: &str) -> Option<&Value>;
@@ -22,30 +29,63 @@ pub trait Node {
 struct BaseNode {
   identifier: String,
   address: SocketAddr,
+  // distinguishes each run of a node, since sequences restart from 0
+  generation: u64,
   sequence: u64,
   values: FxHashMap<String, SequencedValue>,
+  // when each current tombstone was created or received
+  tombstones: FxHashMap<String, Touch>,
 }
 
 impl BaseNode {
-  fn new(identifier: String, address: SocketAddr) -> Self {
+  fn new(identifier: String, address: SocketAddr, generation: u64) -> Self {
     Self {
-      identifier: identifier,
+      identifier,
       address,
+      generation,
       sequence: 0,
       values: FxHashMap::default(),
+      tombstones: FxHashMap::default(),
     }
   }
 
   fn identifier(&self) -> &str { self.identifier.as_str() }
   fn address(&self) -> &SocketAddr { &self.address }
+  fn generation(&self) -> u64 { self.generation }
   fn sequence(&self) -> u64 { self.sequence }
 
   fn digest(&self) -> Digest {
-    (self.identifier.clone(), self.sequence)
+    (self.identifier.clone(), self.generation, self.sequence)
+  }
+
+  fn reset(&mut self, generation: u64) {
+    self.generation = generation;
+    self.sequence = 0;
+    self.values.clear();
+    self.tombstones.clear();
   }
 
   fn get(&self, key: &str) -> Option<&Value> {
-    self.values.get(key).and_then(|(v,_)| Some(v))
+    self.values.get(key).map(|(v,_)| v).filter(|v| !v.is_tombstone())
+  }
+
+  fn insert(&mut self, key: String, value: Value, sequence: u64) {
+    if value.is_tombstone() {
+      self.tombstones.insert(key.clone(), Touch::now());
+    } else {
+      self.tombstones.remove(&key);
+    }
+    self.values.insert(key, (value, sequence));
+  }
+
+  // Remove tombstones older than the grace period.
+  fn collect(&mut self, grace: f64) {
+    let values = &mut self.values;
+    self.tombstones.retain(|key, touch| {
+      if touch.age() <= grace { return true; }
+      values.remove(key);
+      return false;
+    });
   }
 
   fn diff(&self, from: u64) -> Vec<Diff> {
@@ -56,95 +96,259 @@ impl BaseNode {
   }
 }
 
-pub struct SelfNode(BaseNode);
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+  // an application key under the prefix reserved for the protocols' own keys
+  Reserved(String),
+  // tombstones mark deletes, so can only be set through `delete`
+  Tombstone,
+}
+
+impl Error {
+  fn check(key: &str) -> Result<(), Self> {
+    if keys::reserved(key) { return Err(Self::Reserved(key.to_string())); }
+    return Ok(());
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Reserved(key) => { write!(f, "{} is reserved, as it starts with {}", key, keys::RESERVED_PREFIX) }
+      Self::Tombstone => { write!(f, "a tombstone can not be set, delete the key instead") }
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+// The node's own state, with when and after how long any ephemeral values expire.
+pub struct SelfNode(BaseNode, FxHashMap<String, (Touch, f64)>);
 
 impl SelfNode {
+  // Create the node with the current time as its generation.
   pub fn new(identifier: String, address: SocketAddr) -> Self {
-    Self(BaseNode::new(identifier, address))
+    Self::with_generation(identifier, address, utils::timestamp())
+  }
+
+  pub fn with_generation(identifier: String, address: SocketAddr, generation: u64) -> Self {
+    Self(BaseNode::new(identifier, address, generation), FxHashMap::default())
   }
 
-  fn set(&mut self, key: &str, value: Value) {
+  // Move to a newer generation, after seeing one at or beyond ours from a
+  // previous run. Current values are kept, with new sequences, and peers
+  // replace their view of us when they see the new generation.
+  pub fn regenerate(&mut self, seen: u64) {
+    let generation = u64::max(utils::timestamp(), seen + 1);
+    let values: Vec<(String, Value)> = self.0.values.drain()
+      .filter(|(_, (v, _))| !v.is_tombstone())
+      .map(|(k, (v, _))| (k, v))
+      .collect();
+    self.0.reset(generation);
+    for (key, value) in values {
+      self.0.sequence += 1;
+      self.0.insert(key, value, self.0.sequence);
+    }
+  }
+
+  // * Note: keys under the reserved prefix are rejected, being for the protocols,
+  //   as are tombstones, which are only for deletes.
+  pub fn set(&mut self, key: &str, value: Value) -> Result<(), Error> {
+    Error::check(key)?;
+    if value.is_tombstone() { return Err(Error::Tombstone); }
+    self.update(key, value);
+    return Ok(());
+  }
+
+  // Set an ephemeral value, which is deleted if not set again within `ttl` seconds.
+  pub fn set_with_ttl(&mut self, key: &str, value: Value, ttl: f64) -> Result<(), Error> {
+    self.set(key, value)?;
+    self.1.insert(key.to_string(), (Touch::now(), ttl));
+    return Ok(());
+  }
+
+  // Set or delete one of the protocols' own keys, under the reserved prefix.
+  pub(crate) fn set_reserved(&mut self, key: &str, value: Value) { self.update(key, value) }
+  pub(crate) fn delete_reserved(&mut self, key: &str) { self.remove(key) }
+
+  fn update(&mut self, key: &str, value: Value) {
+    self.1.remove(key);
     self.0.sequence += 1;
-    self.0.values.insert(
-      key.to_string(),
-      (value, self.0.sequence)
-    );
+    self.0.insert(key.to_string(), value, self.0.sequence);
   }
+
+  fn remove(&mut self, key: &str) {
+    if self.0.get(key).is_some() { self.update(key, Value::Tombstone); }
+  }
+
+  fn expired(&self, key: &str) -> bool {
+    self.1.get(key).is_some_and(|(touch, ttl)| touch.age() > *ttl)
+  }
+
+  // Delete the key by replacing it with a tombstone, which is gossiped like
+  // any other update until it is collected.
+  pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+    Error::check(key)?;
+    self.remove(key);
+    return Ok(());
+  }
+
+  // Delete any ephemeral values that have expired.
+  pub fn expire(&mut self) {
+    let keys: Vec<String> = self.1.keys()
+      .filter(|k| self.expired(k))
+      .cloned()
+      .collect();
+    for key in keys { self.remove(key.as_str()); }
+  }
+
+  pub fn collect(&mut self, grace: f64) { self.0.collect(grace) }
 }
 
 impl Node for SelfNode {
   fn identifier(&self) -> &str { self.0.identifier() }
   fn address(&self) -> &SocketAddr { self.0.address() }
+  fn generation(&self) -> u64 { self.0.generation() }
   fn sequence(&self) -> u64 { self.0.sequence() }
   fn digest(&self) -> Digest { self.0.digest() }
-  fn get(&self, key: &str) -> Option<&Value> { self.0.get(key) }
-  fn diff(&self, from: u64) -> Vec<Diff> { self.0.diff(from) }
+  fn get(&self, key: &str) -> Option<&Value> {
+    if self.expired(key) { return None; }
+    return self.0.get(key);
+  }
+  // * Note: expired values are left out, though not yet replaced by their
+  //   tombstones, which follow with newer sequences once they are.
+  fn diff(&self, from: u64) -> Vec<Diff> {
+    return self.0.diff(from).into_iter().filter(|(k, _)| !self.expired(k)).collect();
+  }
   fn discardable(&mut self) -> bool { false }
 }
 
-pub struct PeerNode(BaseNode, Option<FailureDetector>, Touch);
+pub struct PeerNode {
+  base: BaseNode,
+  // None while the node is inactive
+  detector: Option<Box<dyn Detector>>,
+  // since when the node has been inactive
+  touch: Touch,
+  strategy: Strategy,
+  suspected: bool,
+}
 
 impl PeerNode {
   pub fn new(identifier: String, address: SocketAddr) -> Self {
-    Self(BaseNode::new(identifier, address), None, Touch::now())
+    Self::with_detector(identifier, address, Strategy::default())
+  }
+
+  // Create the node with the strategy for its failure detectors.
+  pub fn with_detector(identifier: String, address: SocketAddr, detector: Strategy) -> Self {
+    Self {
+      base: BaseNode::new(identifier, address, 0),
+      detector: None,
+      touch: Touch::now(),
+      strategy: detector,
+      suspected: false,
+    }
   }
 
-  pub fn active(&self) -> bool { self.1.is_some() }
+  pub fn active(&self) -> bool { self.detector.is_some() }
+
+  // The failure detector's suspicion of the node, or None while it is inactive.
+  pub fn phi(&self) -> Option<f64> { self.detector.as_ref().map(|d| d.phi()) }
+
+  // Whether the node has left the cluster, in this generation.
+  pub fn left(&self) -> bool { self.base.get(LEFT_KEY) == Some(&Value::Boolean(true)) }
+
+  // Whether the failure detector has decided the active node failed.
+  pub fn failed(&self) -> bool { self.detector.as_ref().is_some_and(|d| d.failed()) }
+
+  // While suspected, the node is left active when its detector fails, for
+  // the suspicion to decide instead.
+  pub fn suspected(&self) -> bool { self.suspected }
+  pub fn set_suspected(&mut self, suspected: bool) { self.suspected = suspected; }
+
+  // Seconds since the node was marked inactive, or None while it is active.
+  pub fn inactive_for(&self) -> Option<f64> {
+    if self.active() { return None; }
+    return Some(self.touch.age());
+  }
 
-  fn mark_inactive(&mut self) {
-    self.1 = None;
-    self.2 = Touch::now();
+  pub fn mark_inactive(&mut self) {
+    self.detector = None;
+    self.suspected = false;
+    self.touch = Touch::now();
   }
 
   fn update_detector(&mut self) {
-    match &mut self.1 {
+    // a node that left stays inactive, whatever it still sends
+    if self.left() { return; }
+    match &mut self.detector {
       // if detector exists, update the detector
       Some(d) => { d.update(); }
       // otherwise, create a new detector
       None => {
-        self.1 = Some(FailureDetector::default());
+        self.detector = Some(self.strategy.detector());
       }
     }
   }
 
   fn current_sequence_for(&self, key: &str) -> u64 {
     let default = (Value::Boolean(false), 0); // default to sequence 0
-    return self.0.values.get(key).unwrap_or(&default).1;
+    return self.base.values.get(key).unwrap_or(&default).1;
   }
 
-  pub fn apply(&mut self, sequence: u64, updates: Vec<Diff>) {
+  // Apply updates from the node, returning the keys with changed values.
+  pub fn apply(&mut self, generation: u64, sequence: u64, updates: Vec<Diff>) -> Vec<String> {
+    let mut changed: Vec<String> = Vec::new();
+
+    // is update from an older run of the node?
+    if generation < self.base.generation { return changed; }
+    // or has the node restarted, so our data is from an older run?
+    if generation > self.base.generation {
+      changed.extend(
+        self.base.values.iter()
+          .filter(|(_, (v, _))| !v.is_tombstone())
+          .map(|(k, _)| k.clone())
+      );
+      self.base.reset(generation);
+    }
     // is update older than our current data?
-    if sequence < self.0.sequence { return; }
+    if sequence < self.base.sequence { return changed; }
 
     self.update_detector();
 
     for (k, (v, s)) in updates {
       if s > self.current_sequence_for(k.as_str()) {
+        // deleting a key we never had changes nothing
+        let visible = !v.is_tombstone() || self.base.get(k.as_str()).is_some();
+        if visible && !changed.contains(&k) { changed.push(k.clone()); }
         // update value when sequence is newer
-        self.0.values.insert(k, (v, s));
+        self.base.insert(k, v, s);
       }
     }
 
-    self.0.sequence = sequence;
+    self.base.sequence = sequence;
+    return changed;
   }
+
+  pub fn collect(&mut self, grace: f64) { self.base.collect(grace) }
 }
 
 impl Node for PeerNode {
-  fn identifier(&self) -> &str { self.0.identifier() }
-  fn address(&self) -> &SocketAddr { self.0.address() }
-  fn sequence(&self) -> u64 { self.0.sequence }
-  fn digest(&self) -> Digest { self.0.digest() }
-  fn get(&self, key: &str) -> Option<&Value> { self.0.get(key) }
-  fn diff(&self, from: u64) -> Vec<Diff> { self.0.diff(from) }
+  fn identifier(&self) -> &str { self.base.identifier() }
+  fn address(&self) -> &SocketAddr { self.base.address() }
+  fn generation(&self) -> u64 { self.base.generation() }
+  fn sequence(&self) -> u64 { self.base.sequence }
+  fn digest(&self) -> Digest { self.base.digest() }
+  fn get(&self, key: &str) -> Option<&Value> { self.base.get(key) }
+  fn diff(&self, from: u64) -> Vec<Diff> { self.base.diff(from) }
 
   fn discardable(&mut self) -> bool {
-    match &self.1 {
+    match &self.detector {
       Some(d) => {
-        if d.failed() { self.mark_inactive(); }
+        if d.failed() && !self.suspected { self.mark_inactive(); }
         return false;
       }
       None => {
-        return self.2.age() > 86_400.0;
+        return self.touch.age() > DISCARD_AFTER;
       }
     }
   }
@@ -155,7 +359,7 @@ mod test {
   use super::*;
   use crate::utils::testing::{addr, advance_clock};
 
-  fn has_change(diff: &Vec<Diff>, key: &str, value: Value, sequence: u64) -> bool {
+  fn has_change(diff: &[Diff], key: &str, value: Value, sequence: u64) -> bool {
     return diff.iter().any(|(k, (v, s))| {
         k == key && *v == value && *s == sequence
     });
@@ -167,18 +371,19 @@ mod test {
     assert_eq!(node.identifier(), "root");
     assert_eq!(node.address().to_string(), "127.1.1.11:3322");
     assert_eq!(node.sequence(), 0);
+    assert!(node.generation() > 1_700_000_000);
 
-    assert_eq!(node.digest(), ("root".into(), 0));
+    assert_eq!(node.digest(), ("root".into(), node.generation(), 0));
     assert!(node.get("buckets").is_none());
     assert!(node.diff(0).is_empty());
   }
 
   #[test]
   fn test_self_node_set() {
-    let mut node = SelfNode::new("root".into(), addr());
-    node.set("buckets", vec![1, 5, 6].into());
+    let mut node = SelfNode::with_generation("root".into(), addr(), 7);
+    node.set("buckets", vec![1, 5, 6].into()).unwrap();
     assert_eq!(node.sequence(), 1);
-    assert_eq!(node.digest(), ("root".into(), 1));
+    assert_eq!(node.digest(), ("root".into(), 7, 1));
 
     let v = node.get("buckets");
     assert!(v.is_some());
@@ -197,9 +402,9 @@ mod test {
   fn test_self_node_multiple_sets() {
     let mut node = SelfNode::new("root".into(), addr());
 
-    node.set("key1", 10.into());
-    node.set("key2", "value".into());
-    node.set("key1", 20.into()); // Overwrite key1
+    node.set("key1", 10.into()).unwrap();
+    node.set("key2", "value".into()).unwrap();
+    node.set("key1", 20.into()).unwrap(); // Overwrite key1
 
     assert_eq!(node.sequence(), 3);
     assert_eq!(node.get("key1"), Some(&20.into()));
@@ -215,9 +420,9 @@ mod test {
   #[test]
   fn test_self_node_partial_diff() {
     let mut node = SelfNode::new("root".into(), addr());
-    node.set("key1", 10.into());
-    node.set("key2", "value".into());
-    node.set("key3", true.into());
+    node.set("key1", 10.into()).unwrap();
+    node.set("key2", "value".into()).unwrap();
+    node.set("key3", true.into()).unwrap();
 
     let diff = node.diff(1);
     assert_eq!(diff.len(), 2);
@@ -225,6 +430,158 @@ mod test {
     assert!(has_change(&diff, "key3", true.into(), 3));
   }
 
+  #[test]
+  fn test_self_node_delete() {
+    let mut node = SelfNode::new("root".into(), addr());
+    node.set("key1", 10.into()).unwrap();
+    node.set("key2", 20.into()).unwrap();
+    node.delete("key1").unwrap();
+
+    assert_eq!(node.sequence(), 3);
+    assert!(node.get("key1").is_none());
+    assert_eq!(node.get("key2"), Some(&20.into()));
+
+    let diff = node.diff(2);
+    assert_eq!(diff.len(), 1);
+    assert!(has_change(&diff, "key1", Value::Tombstone, 3));
+
+    // deleting a missing or deleted key does nothing
+    node.delete("key1").unwrap();
+    node.delete("key3").unwrap();
+    assert_eq!(node.sequence(), 3);
+
+    // setting a deleted key brings it back
+    node.set("key1", 30.into()).unwrap();
+    assert_eq!(node.get("key1"), Some(&30.into()));
+    assert!(node.0.tombstones.is_empty());
+  }
+
+  #[test]
+  fn test_self_node_rejects_reserved_keys() {
+    let mut node = SelfNode::new("root".into(), addr());
+    let error = Err(Error::Reserved(LEFT_KEY.into()));
+    assert_eq!(node.set(LEFT_KEY, true.into()), error);
+    assert_eq!(node.set_with_ttl(LEFT_KEY, true.into(), 1.0), error);
+    assert_eq!(node.sequence(), 0);
+
+    // while the protocols can set them
+    node.set_reserved(LEFT_KEY, true.into());
+    assert_eq!(node.delete(LEFT_KEY), error);
+    assert_eq!(node.get(LEFT_KEY), Some(&true.into()));
+    node.delete_reserved(LEFT_KEY);
+    assert!(node.get(LEFT_KEY).is_none());
+  }
+
+  #[test]
+  fn test_self_node_rejects_tombstones() {
+    let mut node = SelfNode::new("root".into(), addr());
+    assert_eq!(node.set("key", Value::Tombstone), Err(Error::Tombstone));
+    assert_eq!(node.set_with_ttl("key", Value::Tombstone, 1.0), Err(Error::Tombstone));
+    assert_eq!(node.sequence(), 0);
+    assert!(node.diff(0).is_empty());
+  }
+
+  #[test]
+  fn test_self_node_set_with_ttl() {
+    let mut node = SelfNode::new("root".into(), addr());
+    node.set_with_ttl("load", 0.5.into(), 10.0).unwrap();
+    node.set("key", 1.into()).unwrap();
+
+    advance_clock(6.0);
+    node.expire();
+    assert_eq!(node.get("load"), Some(&0.5.into()));
+    assert_eq!(node.sequence(), 2);
+
+    // refreshing the value restarts the ttl
+    node.set_with_ttl("load", 0.7.into(), 10.0).unwrap();
+    advance_clock(6.0);
+    node.expire();
+    assert_eq!(node.get("load"), Some(&0.7.into()));
+
+    // expired values are hidden right away...
+    advance_clock(5.0);
+    assert!(node.get("load").is_none());
+    assert_eq!(node.sequence(), 3);
+
+    // ...and deleted with a tombstone to propagate once expired
+    node.expire();
+    assert_eq!(node.sequence(), 4);
+    assert!(has_change(&node.diff(3), "load", Value::Tombstone, 4));
+    assert_eq!(node.get("key"), Some(&1.into()));
+    assert!(node.1.is_empty());
+  }
+
+  #[test]
+  fn test_self_node_diff_leaves_out_expired() {
+    let mut node = SelfNode::new("root".into(), addr());
+    node.set_with_ttl("load", 0.5.into(), 10.0).unwrap();
+    node.set("key", 1.into()).unwrap();
+    assert_eq!(node.diff(0).len(), 2);
+
+    // expired, but not yet deleted
+    advance_clock(11.0);
+    let diff = node.diff(0);
+    assert_eq!(diff.len(), 1);
+    assert!(has_change(&diff, "key", 1.into(), 2));
+  }
+
+  #[test]
+  fn test_self_node_set_clears_ttl() {
+    let mut node = SelfNode::new("root".into(), addr());
+    node.set_with_ttl("load", 0.5.into(), 10.0).unwrap();
+    node.set("load", 0.6.into()).unwrap();
+    advance_clock(11.0);
+    node.expire();
+    assert_eq!(node.get("load"), Some(&0.6.into()));
+    assert_eq!(node.sequence(), 2);
+  }
+
+  #[test]
+  fn test_self_node_collect() {
+    let mut node = SelfNode::new("root".into(), addr());
+    node.set("key1", 10.into()).unwrap();
+    node.set("key2", 20.into()).unwrap();
+    node.delete("key1").unwrap();
+
+    advance_clock(5.0);
+    node.delete("key2").unwrap();
+
+    node.collect(10.0);
+    assert_eq!(node.diff(0).len(), 2);
+
+    advance_clock(6.0);
+    node.collect(10.0);
+    let diff = node.diff(0);
+    assert_eq!(diff.len(), 1);
+    assert!(has_change(&diff, "key2", Value::Tombstone, 4));
+    assert_eq!(node.sequence(), 4);
+  }
+
+  #[test]
+  fn test_self_node_regenerate() {
+    let mut node = SelfNode::with_generation("root".into(), addr(), 7);
+    node.set("key1", 10.into()).unwrap();
+    node.set("key2", 20.into()).unwrap();
+    node.set("key3", 30.into()).unwrap();
+    node.delete("key2").unwrap();
+
+    // jumps ahead to the current time, past the generation seen
+    node.regenerate(7);
+    assert!(node.generation() > 1_700_000_000);
+    let generation = node.generation();
+    node.regenerate(generation + 5);
+    assert_eq!(node.generation(), generation + 6);
+
+    // with current values resequenced
+    assert_eq!(node.sequence(), 2);
+    let diff = node.diff(0);
+    assert_eq!(diff.len(), 2);
+    assert!(diff.iter().all(|(_, (_, s))| *s <= 2));
+    assert_eq!(node.get("key1"), Some(&10.into()));
+    assert!(node.get("key2").is_none());
+    assert_eq!(node.get("key3"), Some(&30.into()));
+  }
+
   #[test]
   fn test_self_node_is_not_discardable() {
     let mut node = SelfNode::new("root".into(), addr());
@@ -238,7 +595,8 @@ mod test {
     assert_eq!(node.address().to_string(), "127.1.1.11:3322");
     assert_eq!(node.sequence(), 0);
 
-    assert_eq!(node.digest(), ("peer1".into(), 0));
+    assert_eq!(node.generation(), 0);
+    assert_eq!(node.digest(), ("peer1".into(), 0, 0));
     assert!(node.get("buckets").is_none());
     assert!(node.diff(0).is_empty());
   }
@@ -246,7 +604,7 @@ mod test {
   #[test]
   fn test_peer_node_apply() {
     let mut node = PeerNode::new("peer1".to_string(), addr());
-    node.apply(2, vec![
+    node.apply(1, 2, vec![
       ("key1".into(), (10.into(), 1)),
       ("key2".into(), ("value".into(), 2)),
     ]);
@@ -256,22 +614,86 @@ mod test {
     assert_eq!(node.get("key2"), Some(&"value".into()));
   }
 
+  #[test]
+  fn test_peer_node_apply_returns_changes() {
+    let mut node = PeerNode::new("peer1".to_string(), addr());
+    let changed = node.apply(1, 3, vec![
+      ("key1".into(), (10.into(), 1)),
+      ("key2".into(), (Value::Tombstone, 2)),
+      ("key3".into(), (30.into(), 3)),
+    ]);
+    assert_eq!(changed.len(), 2);
+    assert!(changed.contains(&"key1".into()));
+    assert!(changed.contains(&"key3".into()));
+
+    let changed = node.apply(1, 5, vec![
+      ("key1".into(), (Value::Tombstone, 4)),
+      ("key3".into(), (30.into(), 3)),
+    ]);
+    assert_eq!(changed, ["key1"]);
+
+    // a restart changes all the keys we had
+    let mut changed = node.apply(2, 1, vec![("key4".into(), (40.into(), 1))]);
+    changed.sort();
+    assert_eq!(changed, ["key3", "key4"]);
+  }
+
   #[test]
   fn test_peer_node_apply_outdated() {
     let mut node = PeerNode::new("peer1".to_string(), addr());
-    node.apply(5, vec![("key1".into(), (10.into(), 5))]);
-    node.apply(3, vec![("key2".into(), (20.into(), 3))]);
-    node.apply(6, vec![("key1".into(), (99.into(), 5))]);
+    node.apply(1, 5, vec![("key1".into(), (10.into(), 5))]);
+    node.apply(1, 3, vec![("key2".into(), (20.into(), 3))]);
+    node.apply(1, 6, vec![("key1".into(), (99.into(), 5))]);
 
     assert_eq!(node.sequence(), 6);
     assert_eq!(node.get("key1"), Some(&10.into()));
     assert!(node.get("key2").is_none());
   }
 
+  #[test]
+  fn test_peer_node_apply_generations() {
+    let mut node = PeerNode::new("peer1".to_string(), addr());
+    node.apply(5, 3, vec![
+      ("key1".into(), (10.into(), 1)),
+      ("key2".into(), (20.into(), 3)),
+    ]);
+    assert_eq!(node.digest(), ("peer1".into(), 5, 3));
+
+    // updates from an older generation are ignored
+    node.apply(4, 9, vec![("key1".into(), (99.into(), 9))]);
+    assert_eq!(node.digest(), ("peer1".into(), 5, 3));
+    assert_eq!(node.get("key1"), Some(&10.into()));
+
+    // a newer generation replaces everything
+    node.apply(6, 1, vec![("key3".into(), (30.into(), 1))]);
+    assert_eq!(node.digest(), ("peer1".into(), 6, 1));
+    assert!(node.get("key1").is_none());
+    assert!(node.get("key2").is_none());
+    assert_eq!(node.get("key3"), Some(&30.into()));
+  }
+
+  #[test]
+  fn test_peer_node_apply_tombstone() {
+    let mut node = PeerNode::new("peer1".to_string(), addr());
+    node.apply(1, 2, vec![
+      ("key1".into(), (10.into(), 1)),
+      ("key2".into(), (20.into(), 2)),
+    ]);
+    node.apply(1, 3, vec![("key1".into(), (Value::Tombstone, 3))]);
+
+    assert!(node.get("key1").is_none());
+    assert_eq!(node.get("key2"), Some(&20.into()));
+    assert!(has_change(&node.diff(2), "key1", Value::Tombstone, 3));
+
+    advance_clock(11.0);
+    node.collect(10.0);
+    assert!(node.diff(0).iter().all(|(k, _)| k == "key2"));
+  }
+
   #[test]
   fn test_peer_node_diff() {
     let mut node = PeerNode::new("peer1".to_string(), addr());
-    node.apply(3, vec![
+    node.apply(1, 3, vec![
       ("key1".into(), (10.into(), 1)),
       ("key2".into(), (20.into(), 2)),
       ("key3".into(), (30.into(), 3)),
@@ -313,7 +735,7 @@ mod test {
     assert_eq!(node.discardable(), false);
     assert_eq!(node.active(), true);
 
-    let detector = node.1.as_ref().unwrap();
+    let detector = node.detector.as_ref().unwrap();
     assert_eq!(detector.failed(), false);
     // Time passes...
     advance_clock(1e2);
@@ -336,4 +758,39 @@ mod test {
     assert_eq!(node.discardable(), false);
     assert_eq!(node.active(), true);
   }
+
+  #[test]
+  fn test_peer_node_uses_detector_strategy() {
+    use crate::failure_detector::Distribution;
+    let strategy = Strategy::Accrual {
+      threshold: 8.0, interval: 1.0, window: 10,
+      distribution: Distribution::Normal, min_standard_deviation: 0.1,
+    };
+    let mut node = PeerNode::with_detector("peer1".to_string(), addr(), strategy);
+    let mut ewma = PeerNode::new("peer2".to_string(), addr());
+    node.update_detector();
+    ewma.update_detector();
+
+    // the accrual detector is confident sooner that steady updates have stopped
+    advance_clock(2.0);
+    assert_eq!(node.discardable(), false);
+    assert_eq!(ewma.discardable(), false);
+    assert_eq!(node.active(), false);
+    assert_eq!(ewma.active(), true);
+  }
+
+  #[test]
+  fn test_suspected_peer_node_stays_active() {
+    let mut node = PeerNode::new("peer1".to_string(), addr());
+    node.update_detector();
+    node.set_suspected(true);
+    advance_clock(1e2);
+    assert_eq!(node.failed(), true);
+    assert_eq!(node.discardable(), false);
+    assert_eq!(node.active(), true);
+
+    node.mark_inactive();
+    assert_eq!(node.suspected(), false);
+    assert_eq!(node.failed(), false);
+  }
 }
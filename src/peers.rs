@@ -23,6 +23,15 @@ impl Peers {
   }
 
   pub fn len(&self) -> usize { self.list.len() }
+  pub fn is_empty(&self) -> bool { self.list.is_empty() }
+
+  pub fn iter(&self) -> impl Iterator<Item = &PeerNode> {
+    self.list.values()
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut PeerNode> {
+    self.list.values_mut()
+  }
 
   pub fn get(&self, identifier: &str) -> Option<&PeerNode> {
     self.list.get(identifier)
@@ -40,6 +49,10 @@ impl Peers {
     self.list.iter().map(|(_,n)| n.digest()).collect()
   }
 
+  pub fn remove(&mut self, identifier: &str) -> Option<PeerNode> {
+    self.list.shift_remove(identifier)
+  }
+
   pub fn prune(&mut self) {
     self.list.retain(|_,n| !n.discardable());
   }
@@ -64,12 +77,12 @@ impl Peers {
   pub fn targets(&mut self, rng: &mut Rng) -> Vec<SocketAddr> {
     let mut sample = FxHashSet::<SocketAddr>::default();
 
-    if self.len() == 0 {
+    if self.is_empty() {
       return self.roots.clone();
     }
 
     // cycle through all peer nodes
-    self.next().and_then(|n| Some(sample.insert(*n.address())));
+    self.next().map(|n| sample.insert(*n.address()));
 
     // sometimes, add a root
     if !self.roots.is_empty() && rng.rand_float() < 0.2 {
@@ -79,7 +92,7 @@ impl Peers {
     let (mut actives, inactives) = self.partition();
 
     // sometimes, add an inactive
-    if rng.rand_float() < 0.1 {
+    if !inactives.is_empty() && rng.rand_float() < 0.1 {
       sample.insert(*rand::choose(rng, &inactives).address());
     }
 
@@ -100,6 +113,7 @@ impl Peers {
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::utils::rng;
   use crate::utils::testing::{addr, addrs, addr_from};
 
   #[test]
@@ -129,7 +143,7 @@ mod test {
     let peer2 = PeerNode::new("p2".into(), addr_from("127.1.1.20:3322"));
     peers.add(peer2);
     assert_eq!(peers.len(), 2);
-    assert_eq!(peers.digest(), [("p1".into(), 0), ("p2".into(), 0)]);
+    assert_eq!(peers.digest(), [("p1".into(), 0, 0), ("p2".into(), 0, 0)]);
   }
 
   #[test]
@@ -149,4 +163,22 @@ mod test {
     assert_eq!(peers.next().unwrap().identifier(), "p1");
   }
 
+  #[test]
+  fn test_peers_targets_without_inactives() {
+    let mut peers = Peers::new(addrs());
+    for (i, address) in ["127.1.1.20:3322", "127.1.1.21:3322"].iter().enumerate() {
+      let mut peer = PeerNode::new(format!("p{}", i), addr_from(address));
+      peer.apply(1, 1, Vec::new());
+      peers.add(peer);
+    }
+    assert_eq!(peers.actives().len(), 2);
+
+    let mut rng = rng(Some(42));
+    for _ in 0..20 {
+      let targets = peers.targets(&mut rng);
+      assert!(targets.contains(&addr_from("127.1.1.20:3322")));
+      assert!(targets.contains(&addr_from("127.1.1.21:3322")));
+    }
+  }
+
 }
@@ -0,0 +1,220 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use fxhash::FxHashMap;
+
+use crate::gossip::Outbound;
+use crate::message::Message;
+use crate::node::Node;
+use crate::peers::Peers;
+use crate::utils::{Instant, Rng, rand};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+  // how often a peer is probed, and how long a probe has to be acknowledged
+  pub interval: Duration,
+  // how long to wait for a direct ack, before asking other peers to probe
+  pub timeout: Duration,
+  // how many other peers are asked to probe
+  pub indirect_probes: usize,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      interval: Duration::from_secs(1),
+      timeout: Duration::from_millis(300),
+      indirect_probes: 3,
+    }
+  }
+}
+
+struct Probe {
+  target: String,
+  address: SocketAddr,
+  sequence: u64,
+  sent: Instant,
+  // the peers asked to probe it, once the direct probe timed out
+  helpers: Vec<SocketAddr>,
+}
+
+// SWIM style probing: each interval one active peer is pinged directly, and
+// if it does not ack within the timeout, other peers are asked to ping it for
+// us, so a single bad link is not mistaken for a failure. Peers are probed
+// in a shuffled round robin, bounding the time to detect a failure.
+pub struct Prober {
+  cluster: String,
+  config: Option<Config>,
+  sequence: u64,
+  next_probe: Option<Instant>,
+  probe: Option<Probe>,
+  // peers left to probe this cycle
+  order: Vec<String>,
+  // probes sent on behalf of others: the requester and their sequence, and
+  // the target, by ours
+  relays: FxHashMap<u64, (SocketAddr, u64, SocketAddr, Instant)>,
+}
+
+impl Prober {
+  // Create the prober, which only answers others' probes without a config.
+  pub fn new(cluster: &str, config: Option<Config>) -> Self {
+    Self {
+      cluster: cluster.to_string(),
+      config,
+      sequence: 0,
+      next_probe: None,
+      probe: None,
+      order: Vec::new(),
+      relays: FxHashMap::default(),
+    }
+  }
+
+  // Send any probes that are due, returning them with the peer that failed
+  // its probe, if one has.
+  pub fn tick(&mut self, now: Instant, peers: &Peers, rng: &mut Rng) -> (Vec<Outbound>, Option<String>) {
+    let mut messages = Vec::new();
+    let mut failed = None;
+    let Some(config) = self.config.clone() else { return (messages, failed); };
+    self.relays.retain(|_, (_, _, _, sent)| now < *sent + config.interval);
+
+    if let Some(probe) = &mut self.probe {
+      if now >= probe.sent + config.interval {
+        failed = Some(probe.target.clone());
+        self.probe = None;
+      } else if probe.helpers.is_empty() && now >= probe.sent + config.timeout {
+        let mut helpers: Vec<SocketAddr> = peers.actives().into_values()
+          .filter(|n| n.identifier() != probe.target)
+          .map(|n| *n.address())
+          .collect();
+        helpers.sort();
+        rand::shuffle(rng, &mut helpers, config.indirect_probes);
+        helpers.truncate(config.indirect_probes);
+        for address in helpers.iter() {
+          let message = Message::PingReq {
+            cluster: self.cluster.clone(), sequence: probe.sequence, target: probe.address,
+          };
+          messages.push((*address, message));
+        }
+        probe.helpers = helpers;
+      }
+    }
+
+    if self.probe.is_none() && self.next_probe.is_none_or(|n| now >= n) {
+      if let Some((target, address)) = self.next_target(peers, rng) {
+        self.sequence += 1;
+        self.next_probe = Some(now + config.interval);
+        self.probe = Some(Probe { target, address, sequence: self.sequence, sent: now, helpers: Vec::new() });
+        messages.push((address, Message::Ping { cluster: self.cluster.clone(), sequence: self.sequence }));
+      }
+    }
+
+    return (messages, failed);
+  }
+
+  // Answer a ping, probe a peer for another, or take an ack, returning any
+  // messages to send.
+  pub fn handle(&mut self, now: Instant, from: SocketAddr, message: Message) -> Vec<Outbound> {
+    match message {
+      Message::Ping { sequence, .. } => {
+        return vec![(from, Message::PingAck { cluster: self.cluster.clone(), sequence })];
+      }
+      Message::PingReq { sequence, target, .. } => {
+        self.sequence += 1;
+        self.relays.insert(self.sequence, (from, sequence, target, now));
+        return vec![(target, Message::Ping { cluster: self.cluster.clone(), sequence: self.sequence })];
+      }
+      // * Note: acks are only taken from the peer probed, or those asked to probe it.
+      Message::PingAck { sequence, .. } => {
+        let acked = |p: &Probe| p.sequence == sequence && (p.address == from || p.helpers.contains(&from));
+        if self.probe.as_ref().is_some_and(acked) {
+          self.probe = None;
+          return Vec::new();
+        }
+        let Some(&(requester, relayed, target, _)) = self.relays.get(&sequence) else { return Vec::new(); };
+        if target != from { return Vec::new(); }
+        self.relays.remove(&sequence);
+        return vec![(requester, Message::PingAck { cluster: self.cluster.clone(), sequence: relayed })];
+      }
+      _ => { return Vec::new(); }
+    }
+  }
+
+  // The next active peer in the cycle, starting a newly shuffled one as needed.
+  fn next_target(&mut self, peers: &Peers, rng: &mut Rng) -> Option<(String, SocketAddr)> {
+    let actives = peers.actives();
+    if self.order.is_empty() {
+      self.order = actives.keys().map(|id| id.to_string()).collect();
+      self.order.sort();
+      rand::shuffle(rng, &mut self.order, usize::MAX);
+    }
+    while let Some(id) = self.order.pop() {
+      if let Some(n) = actives.get(id.as_str()) { return Some((id, *n.address())); }
+    }
+    return None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::node::PeerNode;
+  use crate::utils;
+  use crate::utils::testing::addr_from;
+
+  #[test]
+  fn test_ping_is_acked() {
+    let mut prober = Prober::new("cluster", None);
+    let from = addr_from("127.1.1.12:3322");
+    let ping = Message::Ping { cluster: "cluster".into(), sequence: 7 };
+    assert_eq!(
+      prober.handle(Instant::now(), from, ping),
+      [(from, Message::PingAck { cluster: "cluster".into(), sequence: 7 })]
+    );
+  }
+
+  #[test]
+  fn test_ping_req_is_relayed() {
+    let mut prober = Prober::new("cluster", None);
+    let (requester, target) = (addr_from("127.1.1.12:3322"), addr_from("127.1.1.13:3322"));
+    let request = Message::PingReq { cluster: "cluster".into(), sequence: 7, target };
+    let outbound = prober.handle(Instant::now(), requester, request);
+    assert_eq!(outbound, [(target, Message::Ping { cluster: "cluster".into(), sequence: 1 })]);
+
+    // the target's ack is passed back with the requester's sequence, once
+    let ack = Message::PingAck { cluster: "cluster".into(), sequence: 1 };
+    assert!(prober.handle(Instant::now(), addr_from("127.1.1.14:3322"), ack.clone()).is_empty());
+    assert_eq!(
+      prober.handle(Instant::now(), target, ack.clone()),
+      [(requester, Message::PingAck { cluster: "cluster".into(), sequence: 7 })]
+    );
+    assert!(prober.handle(Instant::now(), target, ack).is_empty());
+  }
+
+  #[test]
+  fn test_probe_is_only_acked_by_its_target() {
+    let mut prober = Prober::new("cluster", Some(Config::default()));
+    let mut rng = utils::rng(Some(42));
+    let target = addr_from("127.1.1.12:3322");
+    let mut peers = Peers::new(vec![]);
+    let mut b = PeerNode::new("b".into(), target);
+    b.apply(1, 0, vec![]);
+    peers.add(b);
+    let now = Instant::now();
+    let (sent, _) = prober.tick(now, &peers, &mut rng);
+    assert_eq!(sent, [(target, Message::Ping { cluster: "cluster".into(), sequence: 1 })]);
+
+    // an ack from elsewhere leaves the probe to fail
+    let ack = Message::PingAck { cluster: "cluster".into(), sequence: 1 };
+    assert!(prober.handle(now, addr_from("127.1.1.13:3322"), ack.clone()).is_empty());
+    assert!(prober.probe.is_some());
+    assert!(prober.handle(now, target, ack).is_empty());
+    assert!(prober.probe.is_none());
+  }
+
+  #[test]
+  fn test_nothing_is_probed_without_config() {
+    let mut prober = Prober::new("cluster", None);
+    let mut rng = utils::rng(Some(42));
+    assert_eq!(prober.tick(Instant::now(), &Peers::new(vec![]), &mut rng), (vec![], None));
+  }
+}
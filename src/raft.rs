@@ -0,0 +1,2005 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::gossip::Gossip;
+use crate::node::{Node, SelfNode};
+use crate::keys::{DESIRED_ROLE_KEY, LEADER_KEY, ROLE_KEY, TERM_KEY};
+use crate::value::Value;
+use crate::utils::{self, Instant, Rng};
+
+pub mod log;
+pub mod state_machine;
+
+use log::{Configuration, Entry, LogStorage, MemoryLog, Payload, Snapshot};
+use state_machine::StateMachine;
+
+pub type NodeId = String;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+  Follower,
+  // asking whether it could win an election, before starting one
+  PreCandidate,
+  Candidate,
+  Leader,
+}
+
+impl Role {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Role::Follower => { "follower" }
+      Role::PreCandidate => { "pre-candidate" }
+      Role::Candidate => { "candidate" }
+      Role::Leader => { "leader" }
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+  // with whether the leader is transferring leadership to the candidate
+  RequestVote { term: u64, last_log_index: u64, last_log_term: u64, transfer: bool },
+  Vote { term: u64, granted: bool },
+  // for the term the candidate would start, without moving to it
+  PreVote { term: u64, last_log_index: u64, last_log_term: u64 },
+  PreVoteResponse { term: u64, granted: bool },
+  // from the leader transferring its leadership, to start an election straight away
+  TimeoutNow { term: u64 },
+  // also sent without entries as the leader's heartbeat
+  AppendEntries {
+    term: u64,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<Entry>,
+    leader_commit: u64,
+    // the leader's heartbeat round, echoed back to confirm its leadership for reads
+    round: u64,
+  },
+  // with the follower's last matching index on success, or a hint of where
+  // the leader should try from next on failure
+  AppendResponse { term: u64, success: bool, index: u64, round: u64 },
+  // a chunk of the leader's snapshot, for followers missing entries it replaced
+  InstallSnapshot {
+    term: u64,
+    index: u64,
+    last_term: u64,
+    configuration: Configuration,
+    offset: u64,
+    data: Vec<u8>,
+    done: bool,
+  },
+  // with the bytes of the snapshot received so far, or whether it was installed
+  SnapshotResponse { term: u64, index: u64, received: u64, done: bool },
+}
+
+impl Message {
+  pub fn term(&self) -> u64 {
+    match self {
+      Self::RequestVote { term, .. } => { *term }
+      Self::Vote { term, .. } => { *term }
+      Self::PreVote { term, .. } => { *term }
+      Self::PreVoteResponse { term, .. } => { *term }
+      Self::TimeoutNow { term } => { *term }
+      Self::AppendEntries { term, .. } => { *term }
+      Self::AppendResponse { term, .. } => { *term }
+      Self::InstallSnapshot { term, .. } => { *term }
+      Self::SnapshotResponse { term, .. } => { *term }
+    }
+  }
+}
+
+pub type Outbound = (NodeId, Message);
+
+// The state machine's response to a proposal, or why it was not applied.
+pub type Outcome = (u64, Result<Vec<u8>, Error>);
+
+// The index the state machine has applied up to for a read, or why it failed.
+pub type Read = (u64, Result<u64, Error>);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+  // with the leader, if known
+  NotLeader(Option<NodeId>),
+  // the previous configuration change is not yet committed
+  ChangeInProgress,
+  // leadership is being transferred to another node
+  Transferring,
+  NotVoter(NodeId),
+  // the change would leave the cluster without voters
+  NoVoters,
+  // the proposed entry was replaced by another leader's before it was committed
+  Dropped,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NotLeader(Some(leader)) => { write!(f, "not the leader, {} is", leader) }
+      Self::NotLeader(None) => { write!(f, "not the leader, and no leader is known") }
+      Self::ChangeInProgress => { write!(f, "a configuration change is already in progress") }
+      Self::Transferring => { write!(f, "leadership is being transferred") }
+      Self::NotVoter(id) => { write!(f, "{} is not a voter", id) }
+      Self::NoVoters => { write!(f, "the configuration must keep at least one voter") }
+      Self::Dropped => { write!(f, "proposal dropped by a new leader") }
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+// Keeps the voters in line with gossip: active peers are added, and voters
+// inactive for `remove_after` are removed, one change at a time.
+#[derive(Clone, Debug)]
+pub struct MembershipPolicy {
+  pub remove_after: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadMode {
+  // each read waits for a heartbeat round to confirm the leadership
+  ReadIndex,
+  // reads are served without a round while the leader holds a lease, from a
+  // confirmed heartbeat round until followers could elect another leader,
+  // less the fraction of that time clocks may drift apart
+  Lease { clock_drift: f64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+  // Until there is a configuration, elections are only started while exactly
+  // this many voters are known through gossip, so nodes that have not yet
+  // discovered each other, or see different sets of nodes, do not each elect
+  // themselves from their own view.
+  pub bootstrap_expect: usize,
+  // When set, learners that want to be voters are promoted once their log
+  // is within this many entries of the leader's. With a membership policy,
+  // discovered nodes are then also added as learners first, to catch up.
+  pub promote_learners: Option<u64>,
+  pub heartbeat_interval: Duration,
+  // each election timeout is chosen randomly between these
+  pub election_timeout_min: Duration,
+  pub election_timeout_max: Duration,
+  pub max_entries_per_message: usize,
+  // applied entries in the log before they are replaced by a snapshot
+  pub snapshot_threshold: u64,
+  pub snapshot_chunk_size: usize,
+  pub membership: Option<MembershipPolicy>,
+  // When set, followers only start an election once the leader's gossip
+  // failure detector has failed it, and leaders step down once a majority of
+  // voters have failed. Without it, elections rely on the timeouts alone.
+  pub failure_detection: bool,
+  // Followers only start an election once a majority would vote for them,
+  // so a node rejoining after a partition does not disrupt the leader.
+  pub pre_vote: bool,
+  // Leaders step down when they have not heard from a majority of voters
+  // within the minimum election timeout.
+  pub check_quorum: bool,
+  pub read_mode: ReadMode,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      bootstrap_expect: 3,
+      promote_learners: None,
+      heartbeat_interval: Duration::from_millis(250),
+      election_timeout_min: Duration::from_millis(1500),
+      election_timeout_max: Duration::from_millis(3000),
+      max_entries_per_message: 64,
+      snapshot_threshold: 1024,
+      snapshot_chunk_size: 64 * 1024,
+      membership: None,
+      failure_detection: true,
+      pre_vote: false,
+      check_quorum: false,
+      read_mode: ReadMode::ReadIndex,
+    }
+  }
+}
+
+// Replication progress of a follower, tracked by the leader.
+struct Progress {
+  next_index: u64,
+  match_index: u64,
+  // highest index sent since the last response
+  sent_index: u64,
+  // bytes of the snapshot received, while one is being sent
+  snapshot_offset: Option<u64>,
+  // highest heartbeat round acknowledged
+  round: u64,
+}
+
+// Raft consensus for the node, driven like `Gossip`: the application calls
+// `tick(now, gossip)` regularly and sends the returned messages to the named
+// nodes, and passes any messages it receives to `handle(now, from, message)`.
+// Each tick also publishes the node's term, role and leader through gossip.
+//
+// The voters are those of the latest configuration in the log, changed one
+// node at a time, along with learners that are replicated to but do not vote.
+// Until there is one, they are this node and the currently active gossip
+// peers, while exactly `bootstrap_expect` of them are known, and the first
+// leader records them as the initial configuration. Committed commands are
+// applied to the state machine in order, and the responses to this node's
+// own proposals collected for `outcomes()`.
+pub struct Raft {
+  id: NodeId,
+  config: Config,
+  rng: Rng,
+  log: Box<dyn LogStorage>,
+  machine: Box<dyn StateMachine>,
+  role: Role,
+  term: u64,
+  voted_for: Option<NodeId>,
+  leader: Option<NodeId>,
+  voters: FxHashSet<NodeId>,
+  learners: FxHashSet<NodeId>,
+  // this node and the active gossip peers, except those that want to be
+  // learners, as of the last tick
+  known: FxHashSet<NodeId>,
+  // index and members of the latest configuration in the log
+  configuration: Option<(u64, Configuration)>,
+  votes: FxHashSet<NodeId>,
+  progress: FxHashMap<NodeId, Progress>,
+  commit_index: u64,
+  last_applied: u64,
+  // index and term of this node's proposals that are not yet applied
+  proposals: FxHashMap<u64, u64>,
+  outcomes: Vec<Outcome>,
+  // the snapshot being received from the leader
+  incoming: Option<Snapshot>,
+  election_deadline: Option<Instant>,
+  heartbeat_deadline: Option<Instant>,
+  // when we last heard from the leader
+  leader_contact: Option<Instant>,
+  // voters heard from since the last quorum check
+  responded: FxHashSet<NodeId>,
+  quorum_deadline: Option<Instant>,
+  // index of our first entry as leader, which reads must wait for
+  term_start: u64,
+  // heartbeat rounds sent, and the highest a majority of voters acknowledged
+  round: u64,
+  confirmed_round: u64,
+  // when each unconfirmed round was sent, for leases
+  round_times: VecDeque<(u64, Instant)>,
+  lease: Option<Instant>,
+  // id, read index and round to be confirmed of waiting reads
+  pending_reads: Vec<(u64, u64, u64)>,
+  reads: Vec<Read>,
+  next_read: u64,
+  // the node leadership is being transferred to, until when, and whether
+  // it has been told to start an election
+  transfer: Option<(NodeId, Instant, bool)>,
+  // term, role and leader last published through gossip
+  published: Option<(u64, Role, Option<NodeId>)>,
+}
+
+impl Raft {
+  pub fn new(id: &str, config: Config, machine: Box<dyn StateMachine>) -> Self {
+    Self::with_log(id, config, Box::new(MemoryLog::new()), machine)
+  }
+
+  pub fn with_log(
+    id: &str, config: Config, log: Box<dyn LogStorage>, mut machine: Box<dyn StateMachine>
+  ) -> Self {
+    let (term, voted_for) = log.hard_state();
+    let mut applied = 0;
+    if let Some(snapshot) = log.snapshot() {
+      machine.restore(&snapshot.data);
+      applied = snapshot.index;
+    }
+    let mut raft = Self {
+      id: id.to_string(),
+      config,
+      rng: utils::rng(None),
+      log,
+      machine,
+      role: Role::Follower,
+      term,
+      voted_for,
+      leader: None,
+      voters: FxHashSet::default(),
+      learners: FxHashSet::default(),
+      known: FxHashSet::default(),
+      configuration: None,
+      votes: FxHashSet::default(),
+      progress: FxHashMap::default(),
+      commit_index: applied,
+      last_applied: applied,
+      proposals: FxHashMap::default(),
+      outcomes: Vec::new(),
+      incoming: None,
+      election_deadline: None,
+      heartbeat_deadline: None,
+      leader_contact: None,
+      responded: FxHashSet::default(),
+      quorum_deadline: None,
+      term_start: 0,
+      round: 0,
+      confirmed_round: 0,
+      round_times: VecDeque::new(),
+      lease: None,
+      pending_reads: Vec::new(),
+      reads: Vec::new(),
+      next_read: 0,
+      transfer: None,
+      published: None,
+    };
+    raft.load_configuration();
+    return raft;
+  }
+
+  pub fn id(&self) -> &str { self.id.as_str() }
+  pub fn role(&self) -> Role { self.role }
+  pub fn term(&self) -> u64 { self.term }
+  pub fn leader(&self) -> Option<&str> { self.leader.as_deref() }
+  pub fn is_leader(&self) -> bool { self.role == Role::Leader }
+  pub fn log(&self) -> &dyn LogStorage { self.log.as_ref() }
+  pub fn commit_index(&self) -> u64 { self.commit_index }
+  pub fn last_applied(&self) -> u64 { self.last_applied }
+  pub fn machine(&self) -> &dyn StateMachine { self.machine.as_ref() }
+  pub fn voters(&self) -> &FxHashSet<NodeId> { &self.voters }
+  pub fn learners(&self) -> &FxHashSet<NodeId> { &self.learners }
+
+  // Append a command to the log, returning its index, to be replicated from the next tick.
+  // The state machine's response is returned from `outcomes()` once it is applied.
+  pub fn propose(&mut self, command: Vec<u8>) -> Result<u64, Error> {
+    if self.role != Role::Leader { return Err(Error::NotLeader(self.leader.clone())); }
+    if self.transfer.is_some() { return Err(Error::Transferring); }
+    let index = self.log.last_index() + 1;
+    self.proposals.insert(index, self.term);
+    return Ok(self.append(Payload::Command(command)));
+  }
+
+  // Propose adding a voter, or promoting a learner, which counts towards
+  // quorums as soon as it is appended.
+  pub fn add_voter(&mut self, id: &str) -> Result<u64, Error> {
+    let (mut voters, mut learners) = (self.voters.clone(), self.learners.clone());
+    voters.insert(id.to_string());
+    learners.remove(id);
+    return self.change_configuration(voters, learners);
+  }
+
+  pub fn remove_voter(&mut self, id: &str) -> Result<u64, Error> {
+    let mut voters = self.voters.clone();
+    voters.remove(id);
+    return self.change_configuration(voters, self.learners.clone());
+  }
+
+  // Propose adding a learner, or demoting a voter to one.
+  pub fn add_learner(&mut self, id: &str) -> Result<u64, Error> {
+    let (mut voters, mut learners) = (self.voters.clone(), self.learners.clone());
+    voters.remove(id);
+    learners.insert(id.to_string());
+    return self.change_configuration(voters, learners);
+  }
+
+  pub fn remove_learner(&mut self, id: &str) -> Result<u64, Error> {
+    let mut learners = self.learners.clone();
+    learners.remove(id);
+    return self.change_configuration(self.voters.clone(), learners);
+  }
+
+  // Hand leadership over to another voter, once it has caught up with our log.
+  // Proposals are rejected until it has taken over, or the transfer is
+  // abandoned after the minimum election timeout.
+  pub fn transfer_leadership(&mut self, now: Instant, target: &str) -> Result<(), Error> {
+    if self.role != Role::Leader { return Err(Error::NotLeader(self.leader.clone())); }
+    if target == self.id { return Ok(()); }
+    if !self.voters.contains(target) { return Err(Error::NotVoter(target.to_string())); }
+    self.transfer = Some((target.to_string(), now + self.config.election_timeout_min, false));
+    // the target will not wait for our lease to expire
+    self.lease = None;
+    return Ok(());
+  }
+
+  pub fn transferring(&self) -> Option<&str> { self.transfer.as_ref().map(|(t, _, _)| t.as_str()) }
+
+  // Drain the outcomes of this node's proposals, in the order they were applied.
+  pub fn outcomes(&mut self) -> Vec<Outcome> {
+    std::mem::take(&mut self.outcomes)
+  }
+
+  // Start a linearizable read, returning its id. Once the leadership is
+  // confirmed and the state machine has applied everything committed before
+  // the read, it is returned from `reads()` and the state machine can be read.
+  pub fn read(&mut self, now: Instant) -> Result<u64, Error> {
+    if self.role != Role::Leader { return Err(Error::NotLeader(self.leader.clone())); }
+    self.next_read += 1;
+    let index = u64::max(self.commit_index, self.term_start);
+    let leased = self.lease.is_some_and(|l| now < l);
+    let round = match leased {
+      true => { self.confirmed_round }
+      false => {
+        // confirmed by the next heartbeat round, sent from the next tick
+        self.heartbeat_deadline = None;
+        self.round + 1
+      }
+    };
+    self.pending_reads.push((self.next_read, index, round));
+    self.advance_reads();
+    return Ok(self.next_read);
+  }
+
+  // Drain the reads that are ready, or failed.
+  pub fn reads(&mut self) -> Vec<Read> {
+    std::mem::take(&mut self.reads)
+  }
+
+  pub fn tick(&mut self, now: Instant, gossip: &mut Gossip) -> Vec<Outbound> {
+    let messages = self.advance(now, gossip);
+    self.publish(gossip.node_mut());
+    return messages;
+  }
+
+  fn advance(&mut self, now: Instant, gossip: &Gossip) -> Vec<Outbound> {
+    self.known = gossip.peers().actives().into_keys()
+      .filter(|id| !wants_learner(gossip, id))
+      .map(String::from)
+      .collect();
+    if !wants_learner(gossip, &self.id) { self.known.insert(self.id.clone()); }
+    self.update_voters();
+
+    match self.role {
+      Role::Leader => {
+        if self.majority_failed(gossip) || !self.check_quorum(now) {
+          self.become_follower(self.term, None);
+          return Vec::new();
+        }
+        self.advance_commit();
+        if self.transfer.as_ref().is_some_and(|(_, deadline, _)| now >= *deadline) { self.transfer = None; }
+        if self.transfer.is_none() {
+          self.promote_learners(gossip);
+          if let Some(policy) = self.config.membership.clone() { self.apply_policy(&policy, gossip); }
+        }
+        let heartbeat = self.heartbeat_deadline.is_none_or(|d| now >= d);
+        if heartbeat { self.heartbeat_deadline = Some(now + self.config.heartbeat_interval); }
+        let mut messages = self.replicate(now, heartbeat);
+        messages.extend(self.timeout_now());
+        return messages;
+      }
+      Role::Follower | Role::PreCandidate | Role::Candidate => {
+        // removed, or not yet added, so leave elections to the voters
+        if !self.voters.contains(&self.id) { return Vec::new(); }
+        match self.election_deadline {
+          None => {
+            self.reset_election_deadline(now);
+            return Vec::new();
+          }
+          Some(d) if now < d => { return Vec::new(); }
+          Some(_) if self.leader_alive(gossip) => {
+            // missed heartbeats, but gossip still hears from the leader
+            self.reset_election_deadline(now);
+            return Vec::new();
+          }
+          Some(_) if self.config.pre_vote => { return self.start_pre_vote(now); }
+          Some(_) => { return self.start_election(now, false); }
+        }
+      }
+    }
+  }
+
+  pub fn handle(&mut self, now: Instant, from: &str, message: Message) -> Vec<Outbound> {
+    // * Note: vote requests are ignored while we have a leader, so nodes that
+    //   are not voters, such as removed or newly started ones, cannot disrupt it.
+    let request = matches!(message, Message::RequestVote { transfer: false, .. } | Message::PreVote { .. });
+    if request && self.has_current_leader(now) { return Vec::new(); }
+    // pre-votes are for a term the candidate has not started, so are no reason to move to it
+    let pre_vote = matches!(message, Message::PreVote { .. } | Message::PreVoteResponse { granted: true, .. });
+    if !pre_vote && message.term() > self.term {
+      self.become_follower(message.term(), None);
+    }
+
+    match message {
+      Message::RequestVote { term, last_log_index, last_log_term, .. } => {
+        let granted = term == self.term && self.up_to_date(last_log_index, last_log_term) &&
+          self.voted_for.as_deref().is_none_or(|v| v == from);
+        if granted {
+          self.vote(from);
+          self.reset_election_deadline(now);
+        }
+        return vec![(from.to_string(), Message::Vote { term: self.term, granted })];
+      }
+      Message::Vote { term, granted } => {
+        if self.role != Role::Candidate || term != self.term || !granted { return Vec::new(); }
+        self.votes.insert(from.to_string());
+        if self.has_quorum(&self.votes) { return self.become_leader(now); }
+        return Vec::new();
+      }
+      Message::PreVote { term, last_log_index, last_log_term } => {
+        let granted = term > self.term && self.up_to_date(last_log_index, last_log_term);
+        let term = if granted { term } else { self.term };
+        return vec![(from.to_string(), Message::PreVoteResponse { term, granted })];
+      }
+      Message::PreVoteResponse { term, granted } => {
+        if self.role != Role::PreCandidate || term != self.term + 1 || !granted { return Vec::new(); }
+        self.votes.insert(from.to_string());
+        if self.has_quorum(&self.votes) { return self.start_election(now, false); }
+        return Vec::new();
+      }
+      Message::TimeoutNow { term } => {
+        if term != self.term || self.leader.as_deref() != Some(from) { return Vec::new(); }
+        if !self.voters.contains(&self.id) { return Vec::new(); }
+        return self.start_election(now, true);
+      }
+      Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit, round } => {
+        if term < self.term {
+          let response = Message::AppendResponse { term: self.term, success: false, index: 0, round };
+          return vec![(from.to_string(), response)];
+        }
+        if self.role != Role::Follower || self.leader.as_deref() != Some(from) {
+          self.become_follower(term, Some(from.to_string()));
+        }
+        self.reset_election_deadline(now);
+        self.leader_contact = Some(now);
+        let response = self.append_entries(prev_log_index, prev_log_term, entries, leader_commit, round);
+        return vec![(from.to_string(), response)];
+      }
+      Message::AppendResponse { term, success, index, round } => {
+        if self.role != Role::Leader || term != self.term { return Vec::new(); }
+        self.responded.insert(from.to_string());
+        let Some(progress) = self.progress.get_mut(from) else { return Vec::new(); };
+        if round > progress.round {
+          progress.round = round;
+          self.confirm_rounds();
+        }
+        let Some(progress) = self.progress.get_mut(from) else { return Vec::new(); };
+        if success {
+          progress.match_index = u64::max(progress.match_index, index);
+          progress.next_index = progress.match_index + 1;
+        } else {
+          // back up to the follower's hint, and resend from there
+          progress.next_index = u64::max(1, u64::min(progress.next_index - 1, index + 1));
+        }
+        progress.sent_index = progress.next_index - 1;
+        let next_index = progress.next_index;
+        self.advance_commit();
+        let mut messages = self.timeout_now();
+        if next_index <= self.log.last_index() { messages.extend(self.replicate_to(from)); }
+        return messages;
+      }
+      Message::InstallSnapshot { term, index, last_term, configuration, offset, data, done } => {
+        if term < self.term {
+          let response = Message::SnapshotResponse { term: self.term, index, received: 0, done: false };
+          return vec![(from.to_string(), response)];
+        }
+        if self.role != Role::Follower || self.leader.as_deref() != Some(from) {
+          self.become_follower(term, Some(from.to_string()));
+        }
+        self.reset_election_deadline(now);
+        self.leader_contact = Some(now);
+        let snapshot = Snapshot { index, term: last_term, configuration, data };
+        let response = self.install_snapshot(snapshot, offset, done);
+        return vec![(from.to_string(), response)];
+      }
+      Message::SnapshotResponse { term, index, received, done } => {
+        if self.role != Role::Leader || term != self.term { return Vec::new(); }
+        self.responded.insert(from.to_string());
+        let snapshot_index = self.log.first_index() - 1;
+        let Some(progress) = self.progress.get_mut(from) else { return Vec::new(); };
+        if done {
+          progress.snapshot_offset = None;
+          progress.match_index = u64::max(progress.match_index, index);
+          progress.next_index = progress.match_index + 1;
+          self.advance_commit();
+        } else if index == snapshot_index {
+          progress.snapshot_offset = Some(received);
+        } else {
+          // for an older snapshot, so start over with ours
+          progress.snapshot_offset = Some(0);
+        }
+        return self.replicate_to(from);
+      }
+    }
+  }
+
+  fn load_configuration(&mut self) {
+    self.configuration = self.configuration_at(self.log.last_index());
+    self.update_voters();
+  }
+
+  // The latest configuration at or before the index.
+  fn configuration_at(&self, index: u64) -> Option<(u64, Configuration)> {
+    for entry in self.log.entries(self.log.first_index(), index + 1).into_iter().rev() {
+      if let Payload::Configuration(c) = entry.payload { return Some((entry.index, c)); }
+    }
+    return self.log.snapshot()
+      .filter(|s| s.index <= index && !s.configuration.voters.is_empty())
+      .map(|s| (s.index, s.configuration.clone()));
+  }
+
+  fn update_voters(&mut self) {
+    match &self.configuration {
+      Some((_, c)) => {
+        self.voters = c.voters.iter().cloned().collect();
+        self.learners = c.learners.iter().cloned().collect();
+      }
+      None if self.known.len() == self.config.bootstrap_expect => {
+        self.voters = self.known.clone();
+        self.learners.clear();
+      }
+      None => {
+        self.voters.clear();
+        self.learners.clear();
+      }
+    }
+  }
+
+  // Whether the latest configuration is not yet committed.
+  fn changing(&self) -> bool {
+    self.configuration.as_ref().is_some_and(|(index, _)| *index > self.commit_index)
+  }
+
+  fn change_configuration(
+    &mut self, voters: FxHashSet<NodeId>, learners: FxHashSet<NodeId>
+  ) -> Result<u64, Error> {
+    if self.role != Role::Leader { return Err(Error::NotLeader(self.leader.clone())); }
+    if self.transfer.is_some() { return Err(Error::Transferring); }
+    // * Note: the leader must also have committed an entry from its term, so
+    //   it cannot overlap with a change from a previous leader.
+    if self.changing() || self.log.term_at(self.commit_index) != Some(self.term) {
+      return Err(Error::ChangeInProgress);
+    }
+    if voters.is_empty() { return Err(Error::NoVoters); }
+    let index = self.log.last_index() + 1;
+    self.proposals.insert(index, self.term);
+    return Ok(self.append(Payload::Configuration(configuration(&voters, &learners))));
+  }
+
+  // Propose promoting the first learner that wants to be a voter and has caught up.
+  fn promote_learners(&mut self, gossip: &Gossip) {
+    let Some(lag) = self.config.promote_learners else { return; };
+    if self.configuration.is_none() || self.changing() { return; }
+    let last_index = self.log.last_index();
+    let mut promoted: Vec<&NodeId> = self.learners.iter()
+      .filter(|id| !wants_learner(gossip, id))
+      .filter(|id| self.progress.get(*id).is_some_and(|p| p.match_index + lag >= last_index))
+      .collect();
+    promoted.sort();
+    if let Some(id) = promoted.first() {
+      let id = id.to_string();
+      let _ = self.add_voter(&id);
+    }
+  }
+
+  // Propose adding the first active gossip peer that is not a member, or
+  // otherwise removing the first member that has been inactive too long.
+  // * Note: voters pruned from gossip entirely are left for the application
+  //   to remove, as a new leader may not have heard of all its peers yet.
+  fn apply_policy(&mut self, policy: &MembershipPolicy, gossip: &Gossip) {
+    if self.configuration.is_none() || self.changing() { return; }
+    let mut added: Vec<&str> = gossip.peers().actives().into_keys()
+      .filter(|id| !self.voters.contains(*id) && !self.learners.contains(*id))
+      .collect();
+    added.sort();
+    if let Some(id) = added.first() {
+      let id = id.to_string();
+      let learner = wants_learner(gossip, &id) || self.config.promote_learners.is_some();
+      let _ = if learner { self.add_learner(&id) } else { self.add_voter(&id) };
+      return;
+    }
+
+    let remove_after = policy.remove_after.as_secs_f64();
+    let mut removed: Vec<&NodeId> = self.voters.iter().chain(self.learners.iter())
+      .filter(|id| gossip.peers().get(id).and_then(|n| n.inactive_for()).is_some_and(|t| t >= remove_after))
+      .collect();
+    removed.sort();
+    if let Some(id) = removed.first() {
+      let id = id.to_string();
+      let _ = match self.voters.contains(&id) {
+        true => { self.remove_voter(&id) }
+        false => { self.remove_learner(&id) }
+      };
+    }
+  }
+
+  fn vote(&mut self, candidate: &str) {
+    self.voted_for = Some(candidate.to_string());
+    self.log.set_hard_state(self.term, self.voted_for.clone());
+  }
+
+  // Only vote for candidates with a log at least as up to date as ours.
+  fn up_to_date(&self, last_log_index: u64, last_log_term: u64) -> bool {
+    return (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+  }
+
+  // Whether a majority of voters responded since the last check, once one is due.
+  fn check_quorum(&mut self, now: Instant) -> bool {
+    if !self.config.check_quorum || self.quorum_deadline.is_some_and(|d| now < d) { return true; }
+    self.quorum_deadline = Some(now + self.config.election_timeout_min);
+    self.responded.insert(self.id.clone());
+    let quorum = self.has_quorum(&self.responded);
+    self.responded.clear();
+    return quorum;
+  }
+
+  // Whether we are the leader, or heard from one within the minimum election timeout.
+  fn has_current_leader(&self, now: Instant) -> bool {
+    if self.role == Role::Leader { return true; }
+    let timeout = self.config.election_timeout_min;
+    return self.leader.is_some() && self.leader_contact.is_some_and(|t| now < t + timeout);
+  }
+
+  // Whether gossip believes the node failed, so an inactive or suspected peer.
+  // * Note: nodes gossip has not heard of are not counted as failed.
+  // * Note: each detector strategy has its own phi scale, so its verdict is
+  //   used rather than comparing phi with a threshold here.
+  fn failed(&self, gossip: &Gossip, id: &str) -> bool {
+    if !self.config.failure_detection { return false; }
+    return gossip.peers().get(id).is_some_and(|n| !n.active() || n.failed());
+  }
+
+  // Whether the leader we follow is a voter that gossip believes is alive.
+  // * Note: a leader that has been removed no longer holds off elections,
+  //   as it steps down once the change is committed.
+  fn leader_alive(&self, gossip: &Gossip) -> bool {
+    if !self.config.failure_detection { return false; }
+    let Some(leader) = &self.leader else { return false; };
+    return self.voters.contains(leader) && gossip.peers().get(leader).is_some() && !self.failed(gossip, leader);
+  }
+
+  fn majority_failed(&self, gossip: &Gossip) -> bool {
+    let failed = self.voters.iter().filter(|v| **v != self.id && self.failed(gossip, v)).count();
+    return failed > self.voters.len() / 2;
+  }
+
+  fn reset_election_deadline(&mut self, now: Instant) {
+    let min = self.config.election_timeout_min.as_secs_f64();
+    let max = self.config.election_timeout_max.as_secs_f64();
+    let timeout = min + (max - min) * self.rng.rand_float();
+    self.election_deadline = Some(now + Duration::from_secs_f64(timeout));
+  }
+
+  fn has_quorum(&self, nodes: &FxHashSet<NodeId>) -> bool {
+    let count = self.voters.iter().filter(|v| nodes.contains(*v)).count();
+    return count > self.voters.len() / 2;
+  }
+
+  fn others(&self) -> Vec<NodeId> {
+    self.voters.iter().filter(|v| **v != self.id).cloned().collect()
+  }
+
+  // The other voters and the learners, that the log is replicated to.
+  fn followers(&self) -> Vec<NodeId> {
+    self.voters.iter().chain(self.learners.iter()).filter(|v| **v != self.id).cloned().collect()
+  }
+
+  // Set the gossip keys for whatever has changed since last published.
+  fn publish(&mut self, node: &mut SelfNode) {
+    let state = (self.term, self.role, self.leader.clone());
+    let previous = self.published.replace(state.clone());
+    if previous.as_ref().is_none_or(|p| p.0 != state.0) { node.set_reserved(TERM_KEY, Value::Integer(state.0 as i64)); }
+    if previous.as_ref().is_none_or(|p| p.1 != state.1) { node.set_reserved(ROLE_KEY, state.1.name().into()); }
+    if previous.as_ref().is_none_or(|p| p.2 != state.2) {
+      match state.2 {
+        Some(leader) => { node.set_reserved(LEADER_KEY, leader.into()); }
+        None => { node.delete_reserved(LEADER_KEY); }
+      }
+    }
+  }
+
+  fn broadcast(&self, message: Message) -> Vec<Outbound> {
+    self.others().into_iter().map(|v| (v, message.clone())).collect()
+  }
+
+  fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+    if term > self.term {
+      self.term = term;
+      self.voted_for = None;
+      self.log.set_hard_state(self.term, None);
+    }
+    self.role = Role::Follower;
+    self.leader = leader;
+    self.votes.clear();
+    self.progress.clear();
+    self.lease = None;
+    self.round_times.clear();
+    self.transfer = None;
+    // reads wait on a leadership we no longer have
+    let error = Error::NotLeader(self.leader.clone());
+    self.reads.extend(self.pending_reads.drain(..).map(|(id, _, _)| (id, Err(error.clone()))));
+  }
+
+  fn start_pre_vote(&mut self, now: Instant) -> Vec<Outbound> {
+    self.role = Role::PreCandidate;
+    self.leader = None;
+    self.votes.clear();
+    self.votes.insert(self.id.clone());
+    self.reset_election_deadline(now);
+
+    if self.has_quorum(&self.votes) { return self.start_election(now, false); }
+    return self.broadcast(Message::PreVote {
+      term: self.term + 1,
+      last_log_index: self.log.last_index(),
+      last_log_term: self.log.last_term(),
+    });
+  }
+
+  fn start_election(&mut self, now: Instant, transfer: bool) -> Vec<Outbound> {
+    self.term += 1;
+    self.role = Role::Candidate;
+    self.leader = None;
+    let id = self.id.clone();
+    self.vote(&id);
+    self.votes.clear();
+    self.votes.insert(id);
+    self.reset_election_deadline(now);
+
+    if self.has_quorum(&self.votes) { return self.become_leader(now); }
+    return self.broadcast(Message::RequestVote {
+      term: self.term,
+      last_log_index: self.log.last_index(),
+      last_log_term: self.log.last_term(),
+      transfer,
+    });
+  }
+
+  fn become_leader(&mut self, now: Instant) -> Vec<Outbound> {
+    self.role = Role::Leader;
+    self.leader = Some(self.id.clone());
+    self.votes.clear();
+    self.progress.clear();
+    self.responded.clear();
+    self.quorum_deadline = Some(now + self.config.election_timeout_min);
+    self.term_start = self.log.last_index() + 1;
+    // commit an entry from our own term, which also commits any before it,
+    // and records the voters that elected us if there is no configuration yet
+    match self.configuration {
+      Some(_) => { self.append(Payload::Noop); }
+      None => { self.append(Payload::Configuration(configuration(&self.voters, &self.learners))); }
+    }
+    self.heartbeat_deadline = Some(now + self.config.heartbeat_interval);
+    return self.replicate(now, true);
+  }
+
+  fn append(&mut self, payload: Payload) -> u64 {
+    let index = self.log.last_index() + 1;
+    if let Payload::Configuration(c) = &payload {
+      self.configuration = Some((index, c.clone()));
+      self.update_voters();
+    }
+    self.log.append(vec![Entry { index, term: self.term, payload }]);
+    self.advance_commit();
+    return index;
+  }
+
+  // Send entries to followers that are missing them, or to all of them as a heartbeat.
+  fn replicate(&mut self, now: Instant, heartbeat: bool) -> Vec<Outbound> {
+    if heartbeat {
+      self.round += 1;
+      self.round_times.push_back((self.round, now));
+      // without other voters, the round is confirmed already
+      self.confirm_rounds();
+    }
+    let last_index = self.log.last_index();
+    let (voters, learners) = (&self.voters, &self.learners);
+    self.progress.retain(|id, _| voters.contains(id) || learners.contains(id));
+    let mut messages = Vec::new();
+    for id in self.followers() {
+      let progress = self.progress.entry(id.clone()).or_insert(Progress {
+        next_index: last_index + 1,
+        match_index: 0,
+        sent_index: last_index,
+        snapshot_offset: None,
+        round: 0,
+      });
+      if heartbeat || progress.sent_index < last_index {
+        messages.extend(self.replicate_to(&id));
+      }
+    }
+    return messages;
+  }
+
+  fn replicate_to(&mut self, id: &str) -> Vec<Outbound> {
+    let Some(progress) = self.progress.get_mut(id) else { return Vec::new(); };
+    let prev_log_index = progress.next_index - 1;
+    // the entries it needs were replaced by the snapshot, so send that instead
+    if let Some(snapshot) = self.log.snapshot().filter(|s| prev_log_index < s.index) {
+      let offset = progress.snapshot_offset.unwrap_or(0);
+      let end = usize::min(snapshot.data.len(), offset as usize + self.config.snapshot_chunk_size);
+      progress.snapshot_offset = Some(offset);
+      progress.sent_index = self.log.last_index();
+      let message = Message::InstallSnapshot {
+        term: self.term,
+        index: snapshot.index,
+        last_term: snapshot.term,
+        configuration: snapshot.configuration.clone(),
+        offset,
+        data: snapshot.data[offset as usize..end].to_vec(),
+        done: end == snapshot.data.len(),
+      };
+      return vec![(id.to_string(), message)];
+    }
+    let to = progress.next_index + self.config.max_entries_per_message as u64;
+    let entries = self.log.entries(progress.next_index, to);
+    progress.sent_index = prev_log_index + entries.len() as u64;
+    let message = Message::AppendEntries {
+      term: self.term,
+      prev_log_index,
+      prev_log_term: self.log.term_at(prev_log_index).unwrap_or(0),
+      entries,
+      leader_commit: self.commit_index,
+      round: self.round,
+    };
+    return vec![(id.to_string(), message)];
+  }
+
+  fn append_entries(
+    &mut self,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<Entry>,
+    leader_commit: u64,
+    round: u64,
+  ) -> Message {
+    let term = self.term;
+    let last_index = self.log.last_index();
+    // entries replaced by our snapshot are committed, so already match
+    let snapshot_index = self.log.first_index() - 1;
+    let (prev_log_index, prev_log_term, entries) = if prev_log_index < snapshot_index {
+      let entries = entries.into_iter().filter(|e| e.index > snapshot_index).collect();
+      (snapshot_index, self.log.term_at(snapshot_index).unwrap_or(0), entries)
+    } else {
+      (prev_log_index, prev_log_term, entries)
+    };
+    if prev_log_index > last_index {
+      return Message::AppendResponse { term, success: false, index: last_index, round };
+    }
+    if self.log.term_at(prev_log_index) != Some(prev_log_term) {
+      return Message::AppendResponse { term, success: false, index: prev_log_index.saturating_sub(1), round };
+    }
+
+    let match_index = prev_log_index + entries.len() as u64;
+    let mut new_entries = Vec::new();
+    let mut truncated = false;
+    for entry in entries {
+      if !new_entries.is_empty() {
+        new_entries.push(entry);
+        continue;
+      }
+      match self.log.term_at(entry.index) {
+        // already have it
+        Some(t) if t == entry.term => {}
+        // conflicts with ours, so drop ours from here on
+        Some(_) => {
+          self.log.truncate(entry.index);
+          truncated = true;
+          new_entries.push(entry);
+        }
+        None => { new_entries.push(entry); }
+      }
+    }
+    let configuration = new_entries.iter().any(|e| matches!(e.payload, Payload::Configuration(_)));
+    self.log.append(new_entries);
+    if truncated || configuration { self.load_configuration(); }
+
+    // only as far as the entries known to match the leader's
+    let commit_index = u64::min(leader_commit, match_index);
+    if commit_index > self.commit_index {
+      self.commit_index = commit_index;
+      self.apply_committed();
+    }
+    return Message::AppendResponse { term, success: true, index: match_index, round };
+  }
+
+  // Commit the highest index from our term that a majority of voters have.
+  fn advance_commit(&mut self) {
+    if self.role != Role::Leader { return; }
+    let mut indexes: Vec<u64> = self.voters.iter()
+      .map(|v| {
+        if *v == self.id { return self.log.last_index(); }
+        self.progress.get(v).map_or(0, |p| p.match_index)
+      })
+      .collect();
+    indexes.sort_unstable_by(|a, b| b.cmp(a));
+    let Some(&index) = indexes.get(indexes.len() / 2) else { return; };
+    if index > self.commit_index && self.log.term_at(index) == Some(self.term) {
+      self.commit_index = index;
+      self.apply_committed();
+    }
+  }
+
+  // Add the chunk to the snapshot being received, and install it once complete.
+  fn install_snapshot(&mut self, chunk: Snapshot, offset: u64, done: bool) -> Message {
+    let (term, index) = (self.term, chunk.index);
+    if index <= self.last_applied {
+      // nothing to do, we already have these entries
+      return Message::SnapshotResponse { term, index, received: 0, done: true };
+    }
+    let incoming = self.incoming.get_or_insert_with(|| Snapshot { data: Vec::new(), ..chunk.clone() });
+    if incoming.index != index || incoming.term != chunk.term {
+      *incoming = Snapshot { data: Vec::new(), ..chunk.clone() };
+    }
+    if offset != incoming.data.len() as u64 {
+      // out of order, so ask for the chunk we expect
+      return Message::SnapshotResponse { term, index, received: incoming.data.len() as u64, done: false };
+    }
+    incoming.data.extend(chunk.data);
+    if !done {
+      return Message::SnapshotResponse { term, index, received: incoming.data.len() as u64, done: false };
+    }
+
+    let snapshot = self.incoming.take().unwrap();
+    self.machine.restore(&snapshot.data);
+    self.log.save_snapshot(snapshot);
+    self.load_configuration();
+    self.commit_index = u64::max(self.commit_index, index);
+    self.last_applied = index;
+    return Message::SnapshotResponse { term, index, received: 0, done: true };
+  }
+
+  // Replace the applied entries with a snapshot once there are enough of them.
+  fn compact(&mut self) {
+    let snapshot_index = self.log.first_index() - 1;
+    if self.last_applied - snapshot_index < self.config.snapshot_threshold { return; }
+    let Some(term) = self.log.term_at(self.last_applied) else { return; };
+    let configuration = self.configuration_at(self.last_applied).map(|(_, c)| c).unwrap_or_default();
+    let snapshot = Snapshot { index: self.last_applied, term, configuration, data: self.machine.snapshot() };
+    self.log.save_snapshot(snapshot);
+  }
+
+  fn apply_committed(&mut self) {
+    for entry in self.log.entries(self.last_applied + 1, self.commit_index + 1) {
+      let response = match &entry.payload {
+        Payload::Noop => { None }
+        Payload::Command(command) => { Some(self.machine.apply(entry.index, command)) }
+        Payload::Configuration(_) => { Some(Vec::new()) }
+      };
+      self.last_applied = entry.index;
+      // a leader removed from the voters steps down once the change is committed
+      let removed = self.configuration.as_ref()
+        .is_some_and(|(index, c)| *index == entry.index && !c.voters.contains(&self.id));
+      if removed && self.role == Role::Leader { self.become_follower(self.term, None); }
+      // our proposal, unless another leader's entry replaced it
+      let Some(term) = self.proposals.remove(&entry.index) else { continue; };
+      match response {
+        Some(response) if term == entry.term => { self.outcomes.push((entry.index, Ok(response))); }
+        _ => { self.outcomes.push((entry.index, Err(Error::Dropped))); }
+      }
+    }
+    self.compact();
+    self.advance_reads();
+  }
+
+  // Tell the transfer target to start an election, once it has all our entries.
+  fn timeout_now(&mut self) -> Vec<Outbound> {
+    let Some((target, _, sent)) = &mut self.transfer else { return Vec::new(); };
+    let caught_up = self.progress.get(target.as_str()).is_some_and(|p| p.match_index == self.log.last_index());
+    if *sent || !caught_up { return Vec::new(); }
+    *sent = true;
+    return vec![(target.clone(), Message::TimeoutNow { term: self.term })];
+  }
+
+  // Confirm the highest heartbeat round a majority of voters acknowledged,
+  // extending the lease from when it was sent.
+  fn confirm_rounds(&mut self) {
+    let mut rounds: Vec<u64> = self.voters.iter()
+      .map(|v| {
+        if *v == self.id { return self.round; }
+        self.progress.get(v).map_or(0, |p| p.round)
+      })
+      .collect();
+    rounds.sort_unstable_by(|a, b| b.cmp(a));
+    let Some(&round) = rounds.get(rounds.len() / 2) else { return; };
+    if round <= self.confirmed_round { return; }
+    self.confirmed_round = round;
+
+    while let Some((r, sent)) = self.round_times.front().copied() {
+      if r > round { break; }
+      self.round_times.pop_front();
+      if r != round || self.transfer.is_some() { continue; }
+      if let ReadMode::Lease { clock_drift } = self.config.read_mode {
+        self.lease = Some(sent + self.config.election_timeout_min.mul_f64(1.0 - clock_drift));
+      }
+    }
+    self.advance_reads();
+  }
+
+  // Complete the reads with a confirmed round, once their index is applied.
+  fn advance_reads(&mut self) {
+    let (confirmed, applied) = (self.confirmed_round, self.last_applied);
+    let (ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_reads).into_iter()
+      .partition(|(_, index, round)| *round <= confirmed && *index <= applied);
+    self.pending_reads = pending;
+    self.reads.extend(ready.into_iter().map(|(id, index, _)| (id, Ok(index))));
+  }
+}
+
+fn configuration(voters: &FxHashSet<NodeId>, learners: &FxHashSet<NodeId>) -> Configuration {
+  let mut voters: Vec<NodeId> = voters.iter().cloned().collect();
+  let mut learners: Vec<NodeId> = learners.iter().cloned().collect();
+  voters.sort();
+  learners.sort();
+  return Configuration { voters, learners };
+}
+
+// Advertise through gossip that the node only wants to be a learner, or to
+// become a voter (the default).
+pub fn set_desired_role(node: &mut SelfNode, learner: bool) {
+  let role = if learner { "learner" } else { "voter" };
+  node.set_reserved(DESIRED_ROLE_KEY, role.into());
+}
+
+// Whether the node advertises through gossip that it only wants to be a learner.
+fn wants_learner(gossip: &Gossip, id: &str) -> bool {
+  let value = match gossip.node().identifier() == id {
+    true => { gossip.node().get(DESIRED_ROLE_KEY) }
+    false => { gossip.peers().get(id).and_then(|n| n.get(DESIRED_ROLE_KEY)) }
+  };
+  return matches!(value, Some(Value::String(role)) if role == "learner");
+}
+
+#[cfg(test)]
+pub mod testing;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::testing::{Cluster, gossip, journal};
+  use crate::utils::testing::advance_clock;
+
+  fn entry(index: u64, term: u64) -> Entry {
+    Entry { index, term, payload: Payload::Command(vec![index as u8]) }
+  }
+
+  fn request_vote(term: u64, last_log_index: u64, last_log_term: u64) -> Message {
+    Message::RequestVote { term, last_log_index, last_log_term, transfer: false }
+  }
+
+  fn append_entries(
+    term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64
+  ) -> Message {
+    Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit, round: 1 }
+  }
+
+  fn response(term: u64, success: bool, index: u64) -> Vec<Outbound> {
+    vec![("z".into(), Message::AppendResponse { term, success, index, round: 1 })]
+  }
+
+  #[test]
+  fn test_starts_as_follower() {
+    let raft = Raft::new("a", Config::default(), journal());
+    assert_eq!(raft.id(), "a");
+    assert_eq!(raft.role(), Role::Follower);
+    assert_eq!(raft.term(), 0);
+    assert!(raft.leader().is_none());
+  }
+
+  #[test]
+  fn test_single_node_elects_itself() {
+    let mut cluster = Cluster::new(&["a"]);
+    cluster.run(4.0);
+    assert!(cluster.raft("a").is_leader());
+    assert_eq!(cluster.raft("a").term(), 1);
+  }
+
+  #[test]
+  fn test_cluster_elects_one_leader() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().expect("a leader");
+    let term = cluster.raft(&leader).term();
+    for id in ["a", "b", "c"] {
+      assert_eq!(cluster.raft(id).leader(), Some(leader.as_str()));
+      assert_eq!(cluster.raft(id).term(), term);
+    }
+
+    // and the leader keeps its leadership with heartbeats
+    cluster.run(10.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert_eq!(cluster.raft(&leader).term(), term);
+  }
+
+  #[test]
+  fn test_new_leader_elected_when_leader_is_down() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let term = cluster.raft(&leader).term();
+
+    // once gossip also believes the leader failed
+    cluster.isolate(&leader);
+    cluster.run(20.0);
+    let next = cluster.leader_among(&cluster.others(&leader)).expect("a new leader");
+    assert_ne!(next, leader);
+    assert!(cluster.raft(&next).term() > term);
+  }
+
+  #[test]
+  fn test_no_election_until_the_expected_voters_are_known() {
+    let config = Config { bootstrap_expect: 2, ..Config::default() };
+    // two nodes started before they have seen each other
+    let mut nodes: Vec<(Raft, Gossip)> = ["a", "b"].iter()
+      .map(|id| (Raft::new(id, config.clone(), journal()), gossip(id, &[id])))
+      .collect();
+    for _ in 0..40 {
+      advance_clock(0.25);
+      for (raft, gossip) in nodes.iter_mut() {
+        assert!(raft.tick(Instant::now(), gossip).is_empty());
+      }
+    }
+    for (raft, _) in nodes.iter() {
+      assert_eq!(raft.role(), Role::Follower);
+      assert_eq!(raft.term(), 0);
+    }
+
+    // and once they have, they elect a single leader between them
+    let mut cluster = Cluster::with_config(&["a", "b"], config);
+    cluster.run(8.0);
+    assert!(cluster.leader().is_some());
+  }
+
+  #[test]
+  fn test_no_election_with_more_than_the_expected_voters_known() {
+    let config = Config { bootstrap_expect: 3, ..Config::default() };
+    // two nodes whose views differ, so would each start from their own voters
+    let views: [&[&str]; 2] = [&["a", "b", "c", "d"], &["b", "c", "d", "e"]];
+    let mut nodes: Vec<(Raft, Gossip)> = [("a", views[0]), ("e", views[1])].iter()
+      .map(|(id, view)| (Raft::new(id, config.clone(), journal()), gossip(id, view)))
+      .collect();
+    for _ in 0..40 {
+      advance_clock(0.25);
+      for (raft, gossip) in nodes.iter_mut() {
+        assert!(raft.tick(Instant::now(), gossip).is_empty());
+      }
+    }
+    for (raft, _) in nodes.iter() {
+      assert_eq!(raft.role(), Role::Follower);
+      assert_eq!(raft.term(), 0);
+    }
+  }
+
+  #[test]
+  fn test_vote_once_per_term() {
+    let now = Instant::now();
+    let mut raft = Raft::new("a", Config::default(), journal());
+    let vote = raft.handle(now, "b", request_vote(1, 0, 0));
+    assert_eq!(vote, [("b".into(), Message::Vote { term: 1, granted: true })]);
+    let vote = raft.handle(now, "c", request_vote(1, 0, 0));
+    assert_eq!(vote, [("c".into(), Message::Vote { term: 1, granted: false })]);
+    let vote = raft.handle(now, "b", request_vote(1, 0, 0));
+    assert_eq!(vote, [("b".into(), Message::Vote { term: 1, granted: true })]);
+    assert_eq!(raft.log().hard_state(), (1, Some("b".into())));
+
+    // a newer term allows a new vote, and older terms are refused
+    let vote = raft.handle(now, "c", request_vote(2, 0, 0));
+    assert_eq!(vote, [("c".into(), Message::Vote { term: 2, granted: true })]);
+    let vote = raft.handle(now, "b", request_vote(1, 0, 0));
+    assert_eq!(vote, [("b".into(), Message::Vote { term: 2, granted: false })]);
+  }
+
+  #[test]
+  fn test_vote_only_for_up_to_date_logs() {
+    let now = Instant::now();
+    let mut log = MemoryLog::new();
+    log.append(vec![entry(1, 1), entry(2, 3)]);
+    let mut raft = Raft::with_log("a", Config::default(), Box::new(log), journal());
+
+    let vote = raft.handle(now, "b", request_vote(4, 5, 2));
+    assert_eq!(vote, [("b".into(), Message::Vote { term: 4, granted: false })]);
+    let vote = raft.handle(now, "b", request_vote(5, 1, 3));
+    assert_eq!(vote, [("b".into(), Message::Vote { term: 5, granted: false })]);
+    let vote = raft.handle(now, "b", request_vote(6, 2, 3));
+    assert_eq!(vote, [("b".into(), Message::Vote { term: 6, granted: true })]);
+    let vote = raft.handle(now, "c", request_vote(7, 1, 4));
+    assert_eq!(vote, [("c".into(), Message::Vote { term: 7, granted: true })]);
+  }
+
+  #[test]
+  fn test_higher_term_heartbeat_makes_follower() {
+    let mut cluster = Cluster::new(&["a"]);
+    cluster.run(4.0);
+    assert!(cluster.raft("a").is_leader());
+
+    advance_clock(0.1);
+    let raft = cluster.raft_mut("a");
+    raft.handle(Instant::now(), "z", append_entries(5, 0, 0, vec![], 0));
+    assert_eq!(raft.role(), Role::Follower);
+    assert_eq!(raft.term(), 5);
+    assert_eq!(raft.leader(), Some("z"));
+  }
+
+  #[test]
+  fn test_proposals_replicate_and_commit() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    assert!(cluster.raft(&leader).commit_index() >= 1);
+
+    let first = cluster.raft_mut(&leader).propose(b"one".to_vec()).unwrap();
+    let second = cluster.raft_mut(&leader).propose(b"two".to_vec()).unwrap();
+    assert_eq!(second, first + 1);
+    cluster.run(0.1);
+
+    for id in ["a", "b", "c"] {
+      let raft = cluster.raft(id);
+      assert_eq!(raft.log().last_index(), second);
+      assert_eq!(raft.log().entries(first, second + 1)[1].payload, Payload::Command(b"two".to_vec()));
+    }
+    assert_eq!(cluster.raft(&leader).commit_index(), second);
+
+    // followers learn the commit index with the next heartbeat
+    cluster.run(0.5);
+    for id in ["a", "b", "c"] {
+      assert_eq!(cluster.raft(id).commit_index(), second);
+    }
+  }
+
+  #[test]
+  fn test_propose_on_follower_fails() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let follower = cluster.others(&leader)[0].clone();
+    assert_eq!(
+      cluster.raft_mut(&follower).propose(vec![1]),
+      Err(Error::NotLeader(Some(leader)))
+    );
+    assert_eq!(Raft::new("a", Config::default(), journal()).propose(vec![1]), Err(Error::NotLeader(None)));
+  }
+
+  #[test]
+  fn test_no_commit_without_majority() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    for id in cluster.others(&leader) { cluster.isolate(&id); }
+
+    let index = cluster.raft_mut(&leader).propose(vec![1]).unwrap();
+    cluster.run(1.0);
+    assert!(cluster.raft(&leader).commit_index() < index);
+
+    // until the followers are back
+    cluster.heal();
+    cluster.run(1.0);
+    assert_eq!(cluster.raft(&leader).commit_index(), index);
+  }
+
+  #[test]
+  fn test_lagging_follower_catches_up() {
+    let config = Config { max_entries_per_message: 3, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let lagging = cluster.others(&leader)[0].clone();
+
+    cluster.isolate(&lagging);
+    let mut last = 0;
+    for i in 0..20 { last = cluster.raft_mut(&leader).propose(vec![i]).unwrap(); }
+    cluster.run(1.0);
+    assert_eq!(cluster.raft(&leader).commit_index(), last);
+    assert!(cluster.raft(&lagging).log().last_index() < last);
+
+    cluster.heal();
+    cluster.run(2.0);
+    assert_eq!(cluster.raft(&lagging).log().last_index(), last);
+    assert_eq!(cluster.raft(&lagging).commit_index(), last);
+  }
+
+  #[test]
+  fn test_append_entries_resolves_conflicts() {
+    let now = Instant::now();
+    let mut log = MemoryLog::new();
+    log.append(vec![entry(1, 1), entry(2, 1), entry(3, 2), entry(4, 2)]);
+    let mut raft = Raft::with_log("a", Config::default(), Box::new(log), journal());
+
+    // missing entries before the new ones
+    assert_eq!(raft.handle(now, "z", append_entries(3, 6, 3, vec![], 0)), response(3, false, 4));
+    // mismatched term at the previous index
+    assert_eq!(raft.handle(now, "z", append_entries(3, 4, 3, vec![], 0)), response(3, false, 3));
+    // even at the start of the log
+    let mut empty = Raft::with_log("b", Config::default(), Box::new(MemoryLog::new()), journal());
+    assert_eq!(empty.handle(now, "z", append_entries(3, 0, 1, vec![], 0)), response(3, false, 0));
+
+    // conflicting entries are replaced
+    let entries = vec![entry(3, 2), entry(4, 3), entry(5, 3)];
+    assert_eq!(raft.handle(now, "z", append_entries(3, 2, 1, entries, 4)), response(3, true, 5));
+    assert_eq!(raft.log().last_index(), 5);
+    assert_eq!(raft.log().term_at(3), Some(2));
+    assert_eq!(raft.log().term_at(4), Some(3));
+    assert_eq!(raft.commit_index(), 4);
+
+    // and stale or duplicate entries change nothing
+    let entries = vec![entry(2, 1), entry(3, 2)];
+    assert_eq!(raft.handle(now, "z", append_entries(3, 1, 1, entries, 5)), response(3, true, 3));
+    assert_eq!(raft.log().last_index(), 5);
+    assert_eq!(raft.commit_index(), 4);
+  }
+
+  #[test]
+  fn test_hard_state_is_restored() {
+    let mut log = MemoryLog::new();
+    log.set_hard_state(7, Some("b".into()));
+    let raft = Raft::with_log("a", Config::default(), Box::new(log), journal());
+    assert_eq!(raft.term(), 7);
+    let vote = Raft::with_log("a", Config::default(), Box::new(MemoryLog::new()), journal())
+      .handle(Instant::now(), "c", request_vote(7, 0, 0));
+    assert_eq!(vote, [("c".into(), Message::Vote { term: 7, granted: true })]);
+  }
+
+  #[test]
+  fn test_committed_commands_are_applied_in_order() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let first = cluster.raft_mut(&leader).propose(b"x".to_vec()).unwrap();
+    let second = cluster.raft_mut(&leader).propose(b"yz".to_vec()).unwrap();
+    cluster.run(0.5);
+
+    for id in ["a", "b", "c"] {
+      let raft = cluster.raft(id);
+      assert_eq!(raft.last_applied(), second);
+      assert_eq!(raft.machine().snapshot(), b"\x01x\x02yz");
+    }
+
+    // with the responses returned only to the proposer
+    let outcomes = cluster.raft_mut(&leader).outcomes();
+    assert_eq!(outcomes, [(first, Ok(vec![1])), (second, Ok(vec![2]))]);
+    assert!(cluster.raft_mut(&leader).outcomes().is_empty());
+    for id in cluster.others(&leader) {
+      assert!(cluster.raft_mut(&id).outcomes().is_empty());
+    }
+  }
+
+  #[test]
+  fn test_replaced_proposal_is_dropped() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+
+    // proposed while the leader is cut off from the others
+    cluster.isolate(&leader);
+    let index = cluster.raft_mut(&leader).propose(b"lost".to_vec()).unwrap();
+    cluster.run(20.0);
+    let next = cluster.leader_among(&cluster.others(&leader)).unwrap();
+    cluster.raft_mut(&next).propose(b"kept".to_vec()).unwrap();
+    cluster.run(0.5);
+
+    // * Note: the old leader may have moved to a later term while cut off,
+    //   so forcing another election once it is back.
+    cluster.heal();
+    cluster.run(10.0);
+    let next = cluster.leader().unwrap();
+    assert_eq!(cluster.raft(&leader).leader(), Some(next.as_str()));
+    assert_eq!(cluster.raft_mut(&leader).outcomes(), [(index, Err(Error::Dropped))]);
+    assert_eq!(cluster.raft(&leader).machine().snapshot(), cluster.raft(&next).machine().snapshot());
+  }
+
+  #[test]
+  fn test_log_is_compacted_into_snapshots() {
+    let config = Config { snapshot_threshold: 5, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    for i in 0..12 { cluster.raft_mut(&leader).propose(vec![i]).unwrap(); }
+    cluster.run(0.5);
+
+    for id in ["a", "b", "c"] {
+      let raft = cluster.raft(id);
+      let snapshot = raft.log().snapshot().expect("a snapshot");
+      assert!(raft.last_applied() - snapshot.index < 5);
+      assert_eq!(raft.log().first_index(), snapshot.index + 1);
+      assert!(raft.log().entries(1, snapshot.index + 1).is_empty());
+    }
+  }
+
+  #[test]
+  fn test_lagging_follower_installs_snapshot_in_chunks() {
+    let config = Config { snapshot_threshold: 5, snapshot_chunk_size: 4, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let lagging = cluster.others(&leader)[0].clone();
+
+    cluster.isolate(&lagging);
+    for i in 0..20 { cluster.raft_mut(&leader).propose(vec![i; 3]).unwrap(); }
+    cluster.run(1.0);
+    assert!(cluster.raft(&leader).log().first_index() > cluster.raft(&lagging).log().last_index());
+
+    cluster.heal();
+    cluster.run(2.0);
+    let raft = cluster.raft(&lagging);
+    assert_eq!(raft.last_applied(), cluster.raft(&leader).last_applied());
+    assert_eq!(raft.machine().snapshot(), cluster.raft(&leader).machine().snapshot());
+    assert_eq!(raft.log().last_index(), cluster.raft(&leader).log().last_index());
+  }
+
+  #[test]
+  fn test_out_of_order_snapshot_chunks() {
+    let now = Instant::now();
+    let mut raft = Raft::new("a", Config::default(), journal());
+    let chunk = |offset: u64, data: &[u8], done: bool| Message::InstallSnapshot {
+      term: 1, index: 3, last_term: 1, configuration: configuration(&ids(&["z"]), &ids(&[])),
+      offset, data: data.to_vec(), done,
+    };
+    let received = |received: u64, done: bool| {
+      vec![("z".into(), Message::SnapshotResponse { term: 1, index: 3, received, done })]
+    };
+
+    assert_eq!(raft.handle(now, "z", chunk(0, &[1, 7], false)), received(2, false));
+    assert_eq!(raft.handle(now, "z", chunk(3, &[8], true)), received(2, false));
+    assert_eq!(raft.handle(now, "z", chunk(0, &[1, 7], false)), received(2, false));
+    assert_eq!(raft.handle(now, "z", chunk(2, &[1, 8], true)), received(0, true));
+    assert_eq!(raft.last_applied(), 3);
+    assert_eq!(raft.commit_index(), 3);
+    assert_eq!(raft.log().last_index(), 3);
+    assert_eq!(raft.machine().snapshot(), [1, 7, 1, 8]);
+    assert_eq!(raft.voters(), &FxHashSet::from_iter(["z".to_string()]));
+
+    // and entries follow on from the snapshot
+    let entries = vec![entry(4, 1)];
+    assert_eq!(raft.handle(now, "z", append_entries(1, 1, 1, entries, 4)), response(1, true, 4));
+    assert_eq!(raft.last_applied(), 4);
+  }
+
+  #[test]
+  fn test_snapshot_is_restored_on_start() {
+    let mut log = MemoryLog::new();
+    log.save_snapshot(Snapshot { index: 6, term: 2, configuration: Configuration::default(), data: vec![1, 9] });
+    let raft = Raft::with_log("a", Config::default(), Box::new(log), journal());
+    assert_eq!(raft.commit_index(), 6);
+    assert_eq!(raft.last_applied(), 6);
+    assert_eq!(raft.machine().snapshot(), [1, 9]);
+  }
+
+  fn ids(ids: &[&str]) -> FxHashSet<NodeId> {
+    ids.iter().map(|i| i.to_string()).collect()
+  }
+
+  #[test]
+  fn test_first_leader_records_configuration() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let entries = cluster.raft(&leader).log().entries(1, 10);
+    let expected = Configuration { voters: vec!["a".into(), "b".into(), "c".into()], learners: vec![] };
+    assert_eq!(entries[0].payload, Payload::Configuration(expected));
+    for id in ["a", "b", "c"] {
+      assert_eq!(cluster.raft(id).voters(), &ids(&["a", "b", "c"]));
+    }
+
+    // and a new node is not a voter until it is added, nor disrupts the leader
+    let term = cluster.raft(&leader).term();
+    cluster.add("d");
+    cluster.run(8.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert_eq!(cluster.raft(&leader).term(), term);
+    assert_eq!(cluster.raft(&leader).voters(), &ids(&["a", "b", "c"]));
+    assert!(cluster.gossip(&leader).peers().actives().contains_key("d"));
+  }
+
+  #[test]
+  fn test_add_and_remove_voters() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    cluster.add("d");
+
+    let index = cluster.raft_mut(&leader).add_voter("d").unwrap();
+    // one change at a time
+    assert_eq!(cluster.raft_mut(&leader).remove_voter("a"), Err(Error::ChangeInProgress));
+    cluster.run(1.0);
+    assert_eq!(cluster.raft_mut(&leader).outcomes(), [(index, Ok(vec![]))]);
+    for id in ["a", "b", "c", "d"] {
+      assert_eq!(cluster.raft(id).voters(), &ids(&["a", "b", "c", "d"]));
+      assert_eq!(cluster.raft(id).commit_index(), index);
+    }
+
+    // removing the leader makes it step down, for the others to elect a new one
+    let index = cluster.raft_mut(&leader).remove_voter(&leader).unwrap();
+    cluster.run(1.0);
+    assert!(!cluster.raft(&leader).is_leader());
+    assert!(cluster.raft(&leader).commit_index() >= index);
+    cluster.run(10.0);
+    let next = cluster.leader().unwrap();
+    assert_ne!(next, leader);
+    assert!(!cluster.raft(&next).voters().contains(&leader));
+    assert!(!cluster.raft(&leader).is_leader());
+  }
+
+  #[test]
+  fn test_last_voter_cannot_be_removed() {
+    let mut cluster = Cluster::new(&["a"]);
+    cluster.run(8.0);
+    assert_eq!(cluster.leader(), Some("a".to_string()));
+    assert_eq!(cluster.raft_mut("a").remove_voter("a"), Err(Error::NoVoters));
+    assert_eq!(cluster.raft_mut("a").add_learner("a"), Err(Error::NoVoters));
+    cluster.run(1.0);
+    assert_eq!(cluster.leader(), Some("a".to_string()));
+    assert_eq!(cluster.raft("a").voters(), &ids(&["a"]));
+  }
+
+  #[test]
+  fn test_membership_policy_follows_gossip() {
+    let policy = MembershipPolicy { remove_after: Duration::from_secs(5) };
+    let config = Config { membership: Some(policy), ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+
+    // nodes discovered through gossip are added
+    cluster.add("d");
+    cluster.run(4.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert_eq!(cluster.raft(&leader).voters(), &ids(&["a", "b", "c", "d"]));
+    assert_eq!(cluster.raft("d").voters(), &ids(&["a", "b", "c", "d"]));
+
+    // and removed once they have been inactive for long enough
+    let follower = cluster.others(&leader).into_iter().find(|i| i != "d").unwrap();
+    cluster.isolate(&follower);
+    cluster.run(10.0);
+    assert!(cluster.raft(&leader).voters().contains(&follower));
+    cluster.run(15.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert!(!cluster.raft(&leader).voters().contains(&follower));
+    assert_eq!(cluster.raft(&leader).voters().len(), 3);
+  }
+
+  #[test]
+  fn test_learners_replicate_without_voting() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    cluster.add("d");
+    let index = cluster.raft_mut(&leader).add_learner("d").unwrap();
+    cluster.run(1.0);
+    assert_eq!(cluster.raft("d").voters(), &ids(&["a", "b", "c"]));
+    assert_eq!(cluster.raft("d").learners(), &ids(&["d"]));
+    assert_eq!(cluster.raft("d").commit_index(), index);
+
+    // the learner is not needed to commit
+    let follower = cluster.others(&leader).into_iter().find(|i| i != "d").unwrap();
+    cluster.isolate(&follower);
+    let index = cluster.raft_mut(&leader).propose(vec![1]).unwrap();
+    cluster.run(1.0);
+    assert_eq!(cluster.raft(&leader).commit_index(), index);
+    assert_eq!(cluster.raft("d").last_applied(), index);
+
+    // nor enough to commit
+    cluster.isolate(&cluster.others(&leader).into_iter().find(|i| *i != "d" && *i != follower).unwrap());
+    let index = cluster.raft_mut(&leader).propose(vec![2]).unwrap();
+    cluster.run(1.0);
+    assert_eq!(cluster.raft("d").log().last_index(), index);
+    assert!(cluster.raft(&leader).commit_index() < index);
+
+    // and never campaigns, even without a leader
+    cluster.isolate(&leader);
+    cluster.run(10.0);
+    assert_eq!(cluster.raft("d").role(), Role::Follower);
+    assert_eq!(cluster.raft("d").term(), cluster.raft(&leader).term());
+
+    // until promoted
+    cluster.heal();
+    cluster.run(10.0);
+    let leader = cluster.leader().unwrap();
+    let index = cluster.raft_mut(&leader).add_voter("d").unwrap();
+    cluster.run(1.0);
+    assert_eq!(cluster.raft("d").voters(), &ids(&["a", "b", "c", "d"]));
+    assert!(cluster.raft("d").learners().is_empty());
+    assert!(cluster.raft("d").commit_index() >= index);
+  }
+
+  #[test]
+  fn test_learners_promoted_once_caught_up() {
+    let policy = MembershipPolicy { remove_after: Duration::from_secs(5) };
+    let config = Config { membership: Some(policy), promote_learners: Some(0), ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    for n in 0..20 { cluster.raft_mut(&leader).propose(vec![n]).unwrap(); }
+    cluster.run(1.0);
+
+    // discovered nodes join as learners, and become voters once caught up,
+    // unless they only want to be learners
+    cluster.add("d");
+    cluster.add("e");
+    set_desired_role(cluster.gossip_mut("e").node_mut(), true);
+    cluster.run(6.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert_eq!(cluster.raft(&leader).voters(), &ids(&["a", "b", "c", "d"]));
+    assert_eq!(cluster.raft(&leader).learners(), &ids(&["e"]));
+    assert_eq!(cluster.raft("e").last_applied(), cluster.raft(&leader).last_applied());
+
+    // inactive learners are removed
+    cluster.isolate("e");
+    cluster.run(30.0);
+    assert!(cluster.raft(&leader).learners().is_empty());
+  }
+
+  #[test]
+  fn test_leader_is_published_through_gossip() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let term = cluster.raft(&leader).term();
+    let node = cluster.gossip(&leader).node();
+    assert_eq!(node.get(TERM_KEY), Some(&Value::Integer(term as i64)));
+    assert_eq!(node.get(ROLE_KEY), Some(&"leader".into()));
+    assert_eq!(node.get(LEADER_KEY), Some(&leader.as_str().into()));
+    cluster.run(2.0);
+    for id in ["a", "b", "c"] { assert_eq!(cluster.gossip(id).leader(), Some(leader.clone())); }
+
+    // and followers stop claiming it once it is gone
+    cluster.isolate(&leader);
+    cluster.run(20.0);
+    let next = cluster.leader_among(&cluster.others(&leader)).unwrap();
+    for id in cluster.others(&leader) {
+      assert_eq!(cluster.gossip(&id).leader(), Some(next.clone()));
+      let role = if id == next { "leader" } else { "follower" };
+      assert_eq!(cluster.gossip(&id).node().get(ROLE_KEY), Some(&role.into()));
+    }
+  }
+
+  #[test]
+  fn test_no_election_while_gossip_hears_from_leader() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let term = cluster.raft(&leader).term();
+
+    // only the leader's raft messages are lost, as during a long pause
+    cluster.mute(&leader);
+    cluster.run(20.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    for id in ["a", "b", "c"] {
+      assert_eq!(cluster.raft(id).term(), term);
+    }
+
+    // while without the failure detector the followers elect a new leader
+    let config = Config { failure_detection: false, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    cluster.mute(&leader);
+    cluster.run(10.0);
+    assert!(cluster.leader_among(&cluster.others(&leader)).is_some());
+  }
+
+  #[test]
+  fn test_leader_steps_down_when_majority_failed() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+
+    cluster.isolate(&leader);
+    cluster.run(5.0);
+    assert!(cluster.raft(&leader).is_leader());
+    cluster.run(15.0);
+    assert!(!cluster.raft(&leader).is_leader());
+    assert!(cluster.raft(&leader).leader().is_none());
+  }
+
+  #[test]
+  fn test_pre_vote() {
+    let now = Instant::now();
+    let mut raft = Raft::new("a", Config::default(), journal());
+    raft.handle(now, "z", append_entries(2, 0, 0, vec![entry(1, 1), entry(2, 2)], 0));
+    let pre_vote = |term: u64, last_log_index: u64, last_log_term: u64| {
+      Message::PreVote { term, last_log_index, last_log_term }
+    };
+    let response = |to: &str, term: u64, granted: bool| {
+      vec![(to.to_string(), Message::PreVoteResponse { term, granted })]
+    };
+
+    // ignored while the leader is current
+    assert!(raft.handle(now, "b", pre_vote(3, 2, 2)).is_empty());
+    advance_clock(2.0);
+    let now = Instant::now();
+    assert_eq!(raft.handle(now, "b", pre_vote(3, 1, 1)), response("b", 2, false));
+    assert_eq!(raft.handle(now, "b", pre_vote(2, 2, 2)), response("b", 2, false));
+    assert_eq!(raft.handle(now, "b", pre_vote(3, 2, 2)), response("b", 3, true));
+    // without changing term or vote, so pre-votes can be granted to others too
+    assert_eq!(raft.term(), 2);
+    assert_eq!(raft.log().hard_state(), (2, None));
+    assert_eq!(raft.handle(now, "c", pre_vote(3, 2, 2)), response("c", 3, true));
+  }
+
+  #[test]
+  fn test_pre_vote_partitioned_node_does_not_disrupt() {
+    for pre_vote in [true, false] {
+      let config = Config { pre_vote, failure_detection: false, ..Config::default() };
+      let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+      cluster.run(8.0);
+      let leader = cluster.leader().unwrap();
+      let term = cluster.raft(&leader).term();
+      let follower = cluster.others(&leader)[0].clone();
+
+      cluster.isolate(&follower);
+      cluster.run(10.0);
+      cluster.heal();
+      cluster.run(5.0);
+      if pre_vote {
+        // it never got a majority of pre-votes, so kept its term
+        assert_eq!(cluster.leader(), Some(leader.clone()));
+        assert_eq!(cluster.raft(&leader).term(), term);
+        assert_eq!(cluster.raft(&follower).term(), term);
+      } else {
+        // its higher term made the leader step down
+        assert!(cluster.raft(&leader).term() > term + 1);
+      }
+      assert_eq!(cluster.raft(&follower).leader(), cluster.leader().as_deref());
+    }
+  }
+
+  #[test]
+  fn test_check_quorum_with_partition_and_heal() {
+    let config = Config { pre_vote: true, check_quorum: true, failure_detection: false, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c", "d", "e"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let term = cluster.raft(&leader).term();
+    let others = cluster.others(&leader);
+    let minority = [leader.clone(), others[0].clone()];
+    let majority = others[1..].to_vec();
+
+    cluster.partition(&minority);
+    cluster.run(10.0);
+    // the old leader stepped down, and the majority elected a new one
+    assert!(!cluster.raft(&leader).is_leader());
+    let next = cluster.leader_among(&majority).expect("a new leader");
+    assert!(cluster.raft(&next).term() > term);
+    // while the minority could not even start an election
+    assert_eq!(cluster.raft(&minority[1]).term(), term);
+    assert_eq!(cluster.raft(&leader).term(), term);
+
+    cluster.heal();
+    cluster.run(5.0);
+    assert_eq!(cluster.leader(), Some(next.clone()));
+    let term = cluster.raft(&next).term();
+    for id in ["a", "b", "c", "d", "e"] {
+      assert_eq!(cluster.raft(id).leader(), Some(next.as_str()));
+      assert_eq!(cluster.raft(id).term(), term);
+    }
+  }
+
+  #[test]
+  fn test_leader_without_check_quorum_stays_leader() {
+    let config = Config { failure_detection: false, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    cluster.isolate(&leader);
+    cluster.run(10.0);
+    assert!(cluster.raft(&leader).is_leader());
+
+    let config = Config { check_quorum: true, failure_detection: false, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    cluster.isolate(&leader);
+    cluster.run(4.0);
+    assert!(!cluster.raft(&leader).is_leader());
+  }
+
+  #[test]
+  fn test_read_index() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let follower = cluster.others(&leader)[0].clone();
+    let index = cluster.raft_mut(&leader).propose(vec![1]).unwrap();
+
+    // waits for a heartbeat round, and for everything committed to be applied
+    let id = cluster.raft_mut(&leader).read(Instant::now()).unwrap();
+    assert!(cluster.raft_mut(&leader).reads().is_empty());
+    cluster.run(0.05);
+    assert_eq!(cluster.raft_mut(&leader).reads(), [(id, Ok(index - 1))]);
+    let id = cluster.raft_mut(&leader).read(Instant::now()).unwrap();
+    cluster.run(0.05);
+    assert_eq!(cluster.raft_mut(&leader).reads(), [(id, Ok(index))]);
+
+    assert_eq!(
+      cluster.raft_mut(&follower).read(Instant::now()),
+      Err(Error::NotLeader(Some(leader.clone())))
+    );
+  }
+
+  #[test]
+  fn test_read_index_single_node() {
+    let mut cluster = Cluster::new(&["a"]);
+    cluster.run(4.0);
+    let id = cluster.raft_mut("a").read(Instant::now()).unwrap();
+    cluster.run(0.05);
+    assert_eq!(cluster.raft_mut("a").reads(), [(id, Ok(1))]);
+  }
+
+  #[test]
+  fn test_read_fails_when_leadership_is_lost() {
+    let config = Config { check_quorum: true, failure_detection: false, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+
+    cluster.isolate(&leader);
+    let id = cluster.raft_mut(&leader).read(Instant::now()).unwrap();
+    cluster.run(1.0);
+    assert!(cluster.raft_mut(&leader).reads().is_empty());
+    cluster.run(3.0);
+    assert_eq!(cluster.raft_mut(&leader).reads(), [(id, Err(Error::NotLeader(None)))]);
+  }
+
+  #[test]
+  fn test_lease_reads() {
+    let config = Config { read_mode: ReadMode::Lease { clock_drift: 0.1 }, ..Config::default() };
+    let mut cluster = Cluster::with_config(&["a", "b", "c"], config);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let commit_index = cluster.raft(&leader).commit_index();
+
+    // served straight away while the lease holds
+    let id = cluster.raft_mut(&leader).read(Instant::now()).unwrap();
+    assert_eq!(cluster.raft_mut(&leader).reads(), [(id, Ok(commit_index))]);
+
+    // but not once it has expired without heartbeat responses
+    cluster.isolate(&leader);
+    cluster.run(1.4);
+    let id = cluster.raft_mut(&leader).read(Instant::now()).unwrap();
+    assert!(cluster.raft_mut(&leader).reads().is_empty());
+    cluster.heal();
+    cluster.run(0.05);
+    assert_eq!(cluster.raft_mut(&leader).reads(), [(id, Ok(commit_index))]);
+  }
+
+  #[test]
+  fn test_transfer_leadership() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let term = cluster.raft(&leader).term();
+    let target = cluster.others(&leader)[0].clone();
+
+    // once a lagging target has caught up
+    cluster.isolate(&target);
+    for i in 0..5 { cluster.raft_mut(&leader).propose(vec![i]).unwrap(); }
+    cluster.run(0.5);
+    cluster.heal();
+    let last_index = cluster.raft(&leader).log().last_index();
+    cluster.raft_mut(&leader).transfer_leadership(Instant::now(), &target).unwrap();
+    assert_eq!(cluster.raft(&leader).transferring(), Some(target.as_str()));
+    assert_eq!(cluster.raft_mut(&leader).propose(vec![9]), Err(Error::Transferring));
+    assert_eq!(cluster.raft_mut(&leader).add_voter("d"), Err(Error::Transferring));
+
+    cluster.run(0.5);
+    assert_eq!(cluster.leader(), Some(target.clone()));
+    assert_eq!(cluster.raft(&target).term(), term + 1);
+    assert!(cluster.raft(&target).log().last_index() > last_index);
+    assert!(cluster.raft(&leader).transferring().is_none());
+    assert_eq!(cluster.raft_mut(&leader).propose(vec![9]), Err(Error::NotLeader(Some(target))));
+  }
+
+  #[test]
+  fn test_transfer_leadership_targets() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let follower = cluster.others(&leader)[0].clone();
+    let now = Instant::now();
+    assert_eq!(cluster.raft_mut(&leader).transfer_leadership(now, "z"), Err(Error::NotVoter("z".into())));
+    assert_eq!(
+      cluster.raft_mut(&follower).transfer_leadership(now, &leader),
+      Err(Error::NotLeader(Some(leader.clone())))
+    );
+    assert_eq!(cluster.raft_mut(&leader).transfer_leadership(now, &leader), Ok(()));
+    assert!(cluster.raft(&leader).transferring().is_none());
+  }
+
+  #[test]
+  fn test_transfer_leadership_is_abandoned() {
+    let mut cluster = Cluster::new(&["a", "b", "c"]);
+    cluster.run(8.0);
+    let leader = cluster.leader().unwrap();
+    let target = cluster.others(&leader)[0].clone();
+
+    cluster.isolate(&target);
+    cluster.raft_mut(&leader).propose(vec![1]).unwrap();
+    cluster.raft_mut(&leader).transfer_leadership(Instant::now(), &target).unwrap();
+    cluster.run(1.0);
+    assert_eq!(cluster.raft_mut(&leader).propose(vec![2]), Err(Error::Transferring));
+    cluster.run(1.0);
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert!(cluster.raft(&leader).transferring().is_none());
+    assert!(cluster.raft_mut(&leader).propose(vec![2]).is_ok());
+  }
+}
@@ -0,0 +1,231 @@
+use super::NodeId;
+
+// The voters, and the learners that receive the log without voting.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Configuration {
+  pub voters: Vec<NodeId>,
+  pub learners: Vec<NodeId>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+  // appended by a new leader, to commit an entry in its own term
+  Noop,
+  Command(Vec<u8>),
+  // the members from this entry on, replacing the previous configuration
+  Configuration(Configuration),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+  pub index: u64,
+  pub term: u64,
+  pub payload: Payload,
+}
+
+// The state machine's state as of an index, replacing the log up to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+  pub index: u64,
+  pub term: u64,
+  // the configuration as of the index, without voters if there was none
+  pub configuration: Configuration,
+  pub data: Vec<u8>,
+}
+
+// Storage for the Raft log, and the term, vote and latest snapshot that must
+// survive restarts along with it. Indexes start from 1, with index 0 standing
+// for the empty log.
+pub trait LogStorage {
+  // the index of the first entry kept, after those replaced by the snapshot
+  fn first_index(&self) -> u64;
+  fn last_index(&self) -> u64;
+  // the term of the entry at the index, with 0 for index 0, and the snapshot's
+  // term for its index
+  fn term_at(&self, index: u64) -> Option<u64>;
+  // entries from `from` up to, but not including, `to`
+  fn entries(&self, from: u64, to: u64) -> Vec<Entry>;
+  fn append(&mut self, entries: Vec<Entry>);
+  // remove the entries from the index onwards
+  fn truncate(&mut self, from: u64);
+
+  fn snapshot(&self) -> Option<&Snapshot>;
+  // Store the snapshot and discard the entries it replaces. Later entries are
+  // kept only if the log has the snapshot's last entry, otherwise the log is
+  // left empty after the snapshot.
+  fn save_snapshot(&mut self, snapshot: Snapshot);
+
+  fn hard_state(&self) -> (u64, Option<NodeId>);
+  fn set_hard_state(&mut self, term: u64, voted_for: Option<NodeId>);
+
+  fn last_term(&self) -> u64 {
+    self.term_at(self.last_index()).unwrap_or(0)
+  }
+}
+
+#[derive(Default)]
+pub struct MemoryLog {
+  entries: Vec<Entry>,
+  snapshot: Option<Snapshot>,
+  term: u64,
+  voted_for: Option<NodeId>,
+}
+
+impl MemoryLog {
+  pub fn new() -> Self { Self::default() }
+
+  // index and term of the last entry replaced by the snapshot
+  fn offset(&self) -> (u64, u64) {
+    self.snapshot.as_ref().map_or((0, 0), |s| (s.index, s.term))
+  }
+
+  // position of the index in the entries, if after the snapshot
+  fn position(&self, index: u64) -> Option<usize> {
+    let (offset, _) = self.offset();
+    if index <= offset { return None; }
+    return Some((index - offset - 1) as usize);
+  }
+}
+
+impl LogStorage for MemoryLog {
+  fn first_index(&self) -> u64 { self.offset().0 + 1 }
+  fn last_index(&self) -> u64 { self.offset().0 + self.entries.len() as u64 }
+
+  fn term_at(&self, index: u64) -> Option<u64> {
+    let (offset, term) = self.offset();
+    if index == offset { return Some(term); }
+    self.position(index).and_then(|p| self.entries.get(p)).map(|e| e.term)
+  }
+
+  fn entries(&self, from: u64, to: u64) -> Vec<Entry> {
+    let from = self.position(u64::max(from, self.first_index())).unwrap();
+    let to = usize::min(self.entries.len(), self.position(to).unwrap_or(0));
+    if from >= to { return Vec::new(); }
+    return self.entries[from..to].to_vec();
+  }
+
+  fn append(&mut self, entries: Vec<Entry>) {
+    self.entries.extend(entries);
+  }
+
+  fn truncate(&mut self, from: u64) {
+    let from = self.position(u64::max(from, self.first_index())).unwrap();
+    self.entries.truncate(from);
+  }
+
+  fn snapshot(&self) -> Option<&Snapshot> { self.snapshot.as_ref() }
+
+  fn save_snapshot(&mut self, snapshot: Snapshot) {
+    let keep = self.term_at(snapshot.index) == Some(snapshot.term);
+    match self.position(snapshot.index) {
+      Some(position) if keep => { self.entries.drain(..usize::min(position + 1, self.entries.len())); }
+      // already replaced by this or a later snapshot
+      None if snapshot.index <= self.offset().0 => { return; }
+      _ => { self.entries.clear(); }
+    }
+    self.snapshot = Some(snapshot);
+  }
+
+  fn hard_state(&self) -> (u64, Option<NodeId>) { (self.term, self.voted_for.clone()) }
+
+  fn set_hard_state(&mut self, term: u64, voted_for: Option<NodeId>) {
+    self.term = term;
+    self.voted_for = voted_for;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(index: u64, term: u64) -> Entry {
+    Entry { index, term, payload: Payload::Command(vec![index as u8]) }
+  }
+
+  #[test]
+  fn test_empty_log() {
+    let log = MemoryLog::new();
+    assert_eq!(log.last_index(), 0);
+    assert_eq!(log.last_term(), 0);
+    assert_eq!(log.term_at(0), Some(0));
+    assert_eq!(log.term_at(1), None);
+    assert!(log.entries(1, 10).is_empty());
+    assert_eq!(log.hard_state(), (0, None));
+  }
+
+  #[test]
+  fn test_append_and_read() {
+    let mut log = MemoryLog::new();
+    log.append(vec![entry(1, 1), entry(2, 1), entry(3, 2)]);
+    assert_eq!(log.last_index(), 3);
+    assert_eq!(log.last_term(), 2);
+    assert_eq!(log.term_at(2), Some(1));
+    assert_eq!(log.entries(2, 4), [entry(2, 1), entry(3, 2)]);
+    assert_eq!(log.entries(1, 2), [entry(1, 1)]);
+    assert_eq!(log.entries(3, 100), [entry(3, 2)]);
+    assert!(log.entries(4, 5).is_empty());
+    assert!(log.entries(3, 3).is_empty());
+  }
+
+  #[test]
+  fn test_truncate() {
+    let mut log = MemoryLog::new();
+    log.append(vec![entry(1, 1), entry(2, 1), entry(3, 2)]);
+    log.truncate(2);
+    assert_eq!(log.last_index(), 1);
+    assert_eq!(log.term_at(2), None);
+    log.append(vec![entry(2, 3)]);
+    assert_eq!(log.last_term(), 3);
+    log.truncate(1);
+    assert_eq!(log.last_index(), 0);
+  }
+
+  #[test]
+  fn test_hard_state() {
+    let mut log = MemoryLog::new();
+    log.set_hard_state(4, Some("a".into()));
+    assert_eq!(log.hard_state(), (4, Some("a".into())));
+  }
+
+  #[test]
+  fn test_snapshot_replaces_entries() {
+    let mut log = MemoryLog::new();
+    log.append(vec![entry(1, 1), entry(2, 1), entry(3, 2), entry(4, 2)]);
+    log.save_snapshot(Snapshot { index: 2, term: 1, configuration: Configuration::default(), data: vec![7] });
+    assert_eq!(log.snapshot().unwrap().data, [7]);
+    assert_eq!(log.first_index(), 3);
+    assert_eq!(log.last_index(), 4);
+    assert_eq!(log.term_at(1), None);
+    assert_eq!(log.term_at(2), Some(1));
+    assert_eq!(log.term_at(3), Some(2));
+    assert_eq!(log.entries(1, 10), [entry(3, 2), entry(4, 2)]);
+
+    // truncating and appending still work after the snapshot
+    log.truncate(4);
+    log.append(vec![entry(4, 3)]);
+    assert_eq!(log.entries(3, 5), [entry(3, 2), entry(4, 3)]);
+    log.truncate(1);
+    assert_eq!(log.last_index(), 2);
+    assert_eq!(log.last_term(), 1);
+
+    // and older snapshots are ignored
+    log.save_snapshot(Snapshot { index: 1, term: 1, configuration: Configuration::default(), data: vec![] });
+    assert_eq!(log.first_index(), 3);
+  }
+
+  #[test]
+  fn test_snapshot_past_the_log() {
+    let mut log = MemoryLog::new();
+    log.append(vec![entry(1, 1), entry(2, 1), entry(3, 2)]);
+    // a conflicting snapshot replaces everything
+    log.save_snapshot(Snapshot { index: 2, term: 3, configuration: Configuration::default(), data: vec![] });
+    assert_eq!(log.first_index(), 3);
+    assert_eq!(log.last_index(), 2);
+    assert_eq!(log.last_term(), 3);
+
+    log.save_snapshot(Snapshot { index: 10, term: 4, configuration: Configuration::default(), data: vec![] });
+    assert_eq!(log.last_index(), 10);
+    assert_eq!(log.term_at(10), Some(4));
+    assert!(log.entries(1, 20).is_empty());
+  }
+}
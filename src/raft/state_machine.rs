@@ -0,0 +1,12 @@
+// The replicated state, implemented by the application. Committed commands
+// are applied in log order on every node, so implementations must be
+// deterministic: the same commands applied from the same state must always
+// produce the same state and responses.
+pub trait StateMachine {
+  // apply the command committed at the index, returning the response for the proposer
+  fn apply(&mut self, index: u64, command: &[u8]) -> Vec<u8>;
+  // the full state, as of the last applied index
+  fn snapshot(&self) -> Vec<u8>;
+  // replace the full state with one from `snapshot`
+  fn restore(&mut self, snapshot: &[u8]);
+}
@@ -0,0 +1,186 @@
+use std::net::SocketAddr;
+
+use fxhash::FxHashSet;
+
+use crate::gossip::Gossip;
+use crate::message::Message as GossipMessage;
+use crate::node::Node;
+use crate::utils::Instant;
+use crate::utils::testing::{addr_from, advance_clock};
+use crate::value::Value;
+use super::{Config, Message, Raft, Role};
+use super::state_machine::StateMachine;
+
+pub fn address(index: usize) -> String { format!("127.1.1.{}:3322", index + 11) }
+
+// A state machine recording the applied commands, responding with their number.
+#[derive(Default)]
+pub struct Journal(Vec<Vec<u8>>);
+
+impl StateMachine for Journal {
+  fn apply(&mut self, _index: u64, command: &[u8]) -> Vec<u8> {
+    self.0.push(command.to_vec());
+    return vec![self.0.len() as u8];
+  }
+
+  // commands as their length followed by their bytes
+  fn snapshot(&self) -> Vec<u8> {
+    self.0.iter().flat_map(|c| std::iter::once(c.len() as u8).chain(c.iter().copied())).collect()
+  }
+
+  fn restore(&mut self, mut snapshot: &[u8]) {
+    self.0.clear();
+    while let Some((length, rest)) = snapshot.split_first() {
+      let (command, rest) = rest.split_at(*length as usize);
+      self.0.push(command.to_vec());
+      snapshot = rest;
+    }
+  }
+}
+
+pub fn journal() -> Box<dyn StateMachine> { Box::new(Journal::default()) }
+
+// A gossip view with the given peers all active.
+pub fn gossip(id: &str, ids: &[&str]) -> Gossip {
+  let index = ids.iter().position(|i| *i == id).unwrap_or(ids.len());
+  let mut gossip = Gossip::new("cluster", id, addr_from(&address(index)), vec![]);
+  let diffs = ids.iter().enumerate()
+    .filter(|(_, i)| **i != id)
+    .map(|(n, i)| ((i.to_string(), 1, 0), vec![], Some(addr_from(&address(n)))))
+    .collect();
+  let message = GossipMessage::Ack2 { cluster: "cluster".into(), diffs, backlog: 0 };
+  gossip.handle(Instant::now(), addr_from(&address(ids.len())), message);
+  return gossip;
+}
+
+// Raft nodes connected by an instant, lossless network, except for isolated
+// nodes. Their gossip is exchanged over the same network, with each node
+// updating a heartbeat key every round to stay active.
+pub struct Cluster {
+  ids: Vec<String>,
+  nodes: Vec<(Raft, Gossip)>,
+  config: Config,
+  isolated: FxHashSet<String>,
+  // nodes whose raft messages are lost, while their gossip still flows
+  muted: FxHashSet<String>,
+  // nodes only connected to each other, while partitioned from the rest
+  partitioned: FxHashSet<String>,
+}
+
+impl Cluster {
+  pub fn new(ids: &[&str]) -> Self {
+    Self::with_config(ids, Config::default())
+  }
+
+  // * Note: the nodes all start out known to each other, so are expected to
+  //   bootstrap together.
+  pub fn with_config(ids: &[&str], config: Config) -> Self {
+    let config = Config { bootstrap_expect: ids.len(), ..config };
+    Self {
+      ids: ids.iter().map(|i| i.to_string()).collect(),
+      nodes: ids.iter().map(|id| (Raft::new(id, config.clone(), journal()), gossip(id, ids))).collect(),
+      config,
+      isolated: FxHashSet::default(),
+      muted: FxHashSet::default(),
+      partitioned: FxHashSet::default(),
+    }
+  }
+
+  // Start a new node, that knows of the others through gossip.
+  pub fn add(&mut self, id: &str) {
+    self.ids.push(id.to_string());
+    let ids: Vec<&str> = self.ids.iter().map(|i| i.as_str()).collect();
+    self.nodes.push((Raft::new(id, self.config.clone(), journal()), gossip(id, &ids)));
+  }
+
+  fn index(&self, id: &str) -> usize {
+    self.ids.iter().position(|i| i == id).unwrap()
+  }
+
+  fn id_at(&self, at: SocketAddr) -> String {
+    let index = (0..self.ids.len()).find(|n| addr_from(&address(*n)) == at).unwrap();
+    return self.ids[index].clone();
+  }
+
+  pub fn raft(&self, id: &str) -> &Raft { &self.nodes[self.index(id)].0 }
+  pub fn gossip(&self, id: &str) -> &Gossip { &self.nodes[self.index(id)].1 }
+  pub fn raft_mut(&mut self, id: &str) -> &mut Raft {
+    let index = self.index(id);
+    &mut self.nodes[index].0
+  }
+  pub fn gossip_mut(&mut self, id: &str) -> &mut Gossip {
+    let index = self.index(id);
+    &mut self.nodes[index].1
+  }
+
+  pub fn others(&self, id: &str) -> Vec<String> {
+    self.ids.iter().filter(|i| *i != id).cloned().collect()
+  }
+
+  pub fn isolate(&mut self, id: &str) { self.isolated.insert(id.to_string()); }
+  pub fn mute(&mut self, id: &str) { self.muted.insert(id.to_string()); }
+  pub fn partition(&mut self, ids: &[String]) { self.partitioned.extend(ids.iter().cloned()); }
+  pub fn heal(&mut self) {
+    self.isolated.clear();
+    self.muted.clear();
+    self.partitioned.clear();
+  }
+
+  fn connected(&self, from: &str, to: &str) -> bool {
+    !self.isolated.contains(from) && !self.isolated.contains(to) &&
+      self.partitioned.contains(from) == self.partitioned.contains(to)
+  }
+
+  // The leader with the highest term among the nodes, if there is one.
+  pub fn leader_among(&self, ids: &[String]) -> Option<String> {
+    ids.iter()
+      .map(|id| self.raft(id))
+      .filter(|r| r.role() == Role::Leader)
+      .max_by_key(|r| r.term())
+      .map(|r| r.id().to_string())
+  }
+
+  pub fn leader(&self) -> Option<String> { self.leader_among(&self.ids) }
+
+  pub fn deliver(&mut self, mut messages: Vec<(String, String, Message)>) {
+    while let Some((from, to, message)) = messages.pop() {
+      if !self.connected(&from, &to) || self.muted.contains(&from) || self.muted.contains(&to) { continue; }
+      let index = self.index(&to);
+      let replies = self.nodes[index].0.handle(Instant::now(), &from, message);
+      messages.extend(replies.into_iter().map(|(t, m)| (to.clone(), t, m)));
+    }
+  }
+
+  fn deliver_gossip(&mut self, mut messages: Vec<(String, String, GossipMessage)>) {
+    while let Some((from, to, message)) = messages.pop() {
+      if !self.connected(&from, &to) { continue; }
+      let (index, sender) = (self.index(&to), addr_from(&address(self.index(&from))));
+      let replies = self.nodes[index].1.handle(Instant::now(), sender, message);
+      messages.extend(replies.into_iter().map(|(t, m)| (to.clone(), self.id_at(t), m)));
+    }
+  }
+
+  // Advance time in small steps, ticking every node and delivering messages.
+  pub fn run(&mut self, seconds: f64) {
+    let steps = (seconds / 0.05).round() as usize;
+    for _ in 0..steps {
+      advance_clock(0.05);
+      let mut gossip_messages = Vec::new();
+      let mut messages = Vec::new();
+      for (raft, gossip) in self.nodes.iter_mut() {
+        let id = raft.id().to_string();
+        let outbound = gossip.tick(Instant::now());
+        if !outbound.is_empty() {
+          let heartbeat = gossip.node().sequence() as i64;
+          gossip.node_mut().set("heartbeat", Value::Integer(heartbeat)).unwrap();
+        }
+        gossip_messages.extend(outbound.into_iter().map(|(t, m)| (id.clone(), t, m)));
+        let outbound = raft.tick(Instant::now(), gossip);
+        messages.extend(outbound.into_iter().map(|(t, m)| (id.clone(), t, m)));
+      }
+      let gossip_messages = gossip_messages.into_iter().map(|(f, t, m)| (f, self.id_at(t), m)).collect();
+      self.deliver_gossip(gossip_messages);
+      self.deliver(messages);
+    }
+  }
+}
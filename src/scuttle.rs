@@ -0,0 +1,242 @@
+use crate::codec;
+use crate::message::NodeDiff;
+use crate::node::Diff;
+
+// Order in which diffs are selected when they do not all fit in a message,
+// from "Efficient Reconciliation and Flow Control for Anti-Entropy Protocols".
+// * Depth: nodes with the most outstanding updates first, sending as many of
+//   each node's updates as will fit before moving on to the next.
+// * Breadth: updates from all nodes interleaved, lowest sequences first.
+// Either way, each node's updates are sent in sequence order, and a truncated
+// node diff is given the sequence of its last included update. The receiver
+// is then left with a consistent prefix of that node's state, and requests
+// the rest in a later round.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+  Depth,
+  Breadth,
+}
+
+struct Candidate {
+  diff: NodeDiff,
+  header: usize,
+  included: usize,
+  closed: bool,
+}
+
+impl Candidate {
+  fn new((digest, mut updates, address): NodeDiff) -> Self {
+    updates.sort_by_key(|(_, (_, s))| *s);
+    let header = codec::node_diff_len(&digest, &address, updates.len());
+    Self { diff: (digest, updates, address), header, included: 0, closed: false }
+  }
+
+  fn updates(&self) -> &Vec<Diff> { &self.diff.1 }
+
+  // Size of including the next update, plus the node header if it is the first.
+  fn next_len(&self) -> usize {
+    let header = if self.included == 0 { self.header } else { 0 };
+    return header + codec::diff_len(&self.updates()[self.included]);
+  }
+
+  fn complete(&self) -> bool { self.included == self.updates().len() }
+
+  fn into_diff(self) -> Option<NodeDiff> {
+    if self.included == 0 { return None; }
+    let ((identifier, generation, mut sequence), mut updates, address) = self.diff;
+    if self.included < updates.len() {
+      updates.truncate(self.included);
+      sequence = updates[self.included - 1].1.1;
+    }
+    return Some(((identifier, generation, sequence), updates, address));
+  }
+}
+
+fn include(c: &mut Candidate, remaining: &mut usize, count: &mut usize) {
+  let length = c.next_len();
+  if length <= *remaining {
+    *remaining -= length;
+    *count -= 1;
+    c.included += 1;
+  } else {
+    // skipping an update would break the sequence prefix
+    c.closed = true;
+  }
+}
+
+// Add updates from the candidates while they fit in the remaining bytes and
+// count, taking at most `share` updates from any one node.
+fn fill(candidates: &mut [Candidate], remaining: &mut usize, count: &mut usize, share: usize, order: Order) {
+  let open = |c: &Candidate| !c.closed && !c.complete() && c.included < share;
+
+  match order {
+    Order::Depth => {
+      for c in candidates.iter_mut() {
+        while *count > 0 && open(c) { include(c, remaining, count); }
+      }
+    }
+    Order::Breadth => {
+      while *count > 0 {
+        // next update with the lowest sequence across all nodes still open
+        let next = candidates.iter_mut()
+          .filter(|c| open(c))
+          .min_by_key(|c| c.updates()[c.included].1.1);
+        match next {
+          None => { break; }
+          Some(c) => { include(c, remaining, count); }
+        }
+      }
+    }
+  }
+}
+
+// Select the diffs to send within `budget` bytes and at most `limit` updates,
+// and return them with the number of updates left out.
+// * Note: when the limit is reached, every node first gets an equal share of
+//   it, so a single node with many updates cannot starve the others.
+pub fn pack(diffs: Vec<NodeDiff>, budget: usize, limit: usize, order: Order) -> (Vec<NodeDiff>, usize) {
+  let mut remaining = budget;
+  let mut packed = Vec::new();
+  let mut candidates = Vec::new();
+
+  for diff in diffs {
+    let candidate = Candidate::new(diff);
+    if candidate.updates().is_empty() {
+      // node diffs without updates only introduce a node, so include them if they fit
+      if candidate.header <= remaining {
+        remaining -= candidate.header;
+        packed.push(candidate.diff);
+      }
+      continue;
+    }
+    candidates.push(candidate);
+  }
+
+  if order == Order::Depth {
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.updates().len()));
+  }
+
+  let total: usize = candidates.iter().map(|c| c.updates().len()).sum();
+  let mut count = limit;
+  if total > limit && !candidates.is_empty() {
+    let share = usize::max(1, limit / candidates.len());
+    fill(&mut candidates, &mut remaining, &mut count, share, order);
+  }
+  fill(&mut candidates, &mut remaining, &mut count, usize::MAX, order);
+
+  let included: usize = candidates.iter().map(|c| c.included).sum();
+  packed.extend(candidates.into_iter().filter_map(|c| c.into_diff()));
+  return (packed, total - included);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::addr;
+  use crate::value::Value;
+
+  fn node_diff(identifier: &str, sequences: &[u64]) -> NodeDiff {
+    let updates = sequences.iter()
+      .map(|&s| (format!("k{}", s), (Value::Integer(s as i64), s)))
+      .collect();
+    ((identifier.into(), 1, *sequences.iter().max().unwrap_or(&0)), updates, None)
+  }
+
+  fn size(diffs: &[NodeDiff]) -> usize {
+    diffs.iter().map(|(digest, updates, address)| {
+      codec::node_diff_len(digest, address, updates.len()) +
+        updates.iter().map(codec::diff_len).sum::<usize>()
+    }).sum()
+  }
+
+  fn sequences(diff: &NodeDiff) -> Vec<u64> {
+    diff.1.iter().map(|(_, (_, s))| *s).collect()
+  }
+
+  #[test]
+  fn test_pack_everything_that_fits() {
+    let diffs = vec![node_diff("a", &[3, 1, 2]), node_diff("b", &[5])];
+    for order in [Order::Depth, Order::Breadth] {
+      let (packed, left) = pack(diffs.clone(), size(&diffs), usize::MAX, order);
+      assert_eq!(left, 0);
+      assert_eq!(packed.len(), 2);
+      let a = packed.iter().find(|d| d.0.0 == "a").unwrap();
+      assert_eq!(a.0.2, 3);
+      assert_eq!(sequences(a), [1, 2, 3]);
+    }
+  }
+
+  #[test]
+  fn test_pack_depth_prefers_nodes_with_most_updates() {
+    let a = node_diff("a", &[1, 2]);
+    let b = node_diff("b", &[4, 1, 2, 3]);
+    let budget = size(&[node_diff("b", &[1, 2, 3])]);
+
+    let (packed, left) = pack(vec![a, b], budget, usize::MAX, Order::Depth);
+    assert_eq!(left, 3);
+    assert_eq!(packed.len(), 1);
+    // truncated to a consistent prefix of b
+    assert_eq!(packed[0].0, ("b".into(), 1, 3));
+    assert_eq!(sequences(&packed[0]), [1, 2, 3]);
+  }
+
+  #[test]
+  fn test_pack_breadth_prefers_lowest_sequences() {
+    let a = node_diff("a", &[1, 2]);
+    let b = node_diff("b", &[4, 1, 2, 3]);
+    let budget = size(&[node_diff("a", &[1]), node_diff("b", &[1, 2])]);
+
+    let (packed, left) = pack(vec![a, b], budget, usize::MAX, Order::Breadth);
+    assert_eq!(left, 3);
+    assert_eq!(packed.len(), 2);
+    let a = packed.iter().find(|d| d.0.0 == "a").unwrap();
+    let b = packed.iter().find(|d| d.0.0 == "b").unwrap();
+    assert_eq!(a.0.2 + b.0.2, 3);
+    assert_eq!(sequences(a).len() + sequences(b).len(), 3);
+    assert_eq!(sequences(b)[0], 1);
+  }
+
+  #[test]
+  fn test_pack_keeps_introductions_that_fit() {
+    let empty: NodeDiff = (("c".into(), 1, 0), vec![], Some(addr()));
+    let budget = size(std::slice::from_ref(&empty));
+    let (packed, left) = pack(vec![empty.clone(), node_diff("a", &[1])], budget, usize::MAX, Order::Depth);
+    assert_eq!(left, 1);
+    assert_eq!(packed, [empty]);
+  }
+
+  #[test]
+  fn test_pack_with_no_budget() {
+    let (packed, left) = pack(vec![node_diff("a", &[1, 2])], 0, usize::MAX, Order::Breadth);
+    assert!(packed.is_empty());
+    assert_eq!(left, 2);
+  }
+
+  #[test]
+  fn test_pack_limit_shares_between_nodes() {
+    let chatty = node_diff("a", &(1..=20).collect::<Vec<_>>());
+    let quiet = node_diff("b", &[1, 2]);
+    let other = node_diff("c", &[7]);
+
+    for order in [Order::Depth, Order::Breadth] {
+      let diffs = vec![chatty.clone(), quiet.clone(), other.clone()];
+      let (packed, left) = pack(diffs, usize::MAX, 6, order);
+      assert_eq!(left, 23 - 6);
+      let find = |id: &str| packed.iter().find(|d| d.0.0 == id).unwrap().clone();
+      assert_eq!(sequences(&find("b")), [1, 2]);
+      assert_eq!(sequences(&find("c")), [7]);
+      assert_eq!(find("a").0.2, 3);
+      assert_eq!(sequences(&find("a")), [1, 2, 3]);
+    }
+  }
+
+  #[test]
+  fn test_pack_limit_unused_share_goes_to_others() {
+    let chatty = node_diff("a", &(1..=20).collect::<Vec<_>>());
+    let quiet = node_diff("b", &[1]);
+    let (packed, left) = pack(vec![chatty, quiet], usize::MAX, 10, Order::Depth);
+    assert_eq!(left, 11);
+    let a = packed.iter().find(|d| d.0.0 == "a").unwrap();
+    assert_eq!(sequences(a).len(), 9);
+  }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use crate::node::Node;
+use crate::keys::{INCARNATION_KEY, SUSPECT_PREFIX};
+use crate::value::Value;
+
+
+#[derive(Clone, Debug)]
+pub struct Config {
+  // how long a suspected peer has to refute it, shrinking from the maximum
+  // to the minimum as more peers independently suspect it
+  pub min_timeout: Duration,
+  pub max_timeout: Duration,
+  // the number of independent suspicions expected, at which the timeout is the minimum
+  pub confirmations: usize,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      min_timeout: Duration::from_secs(2),
+      max_timeout: Duration::from_secs(12),
+      confirmations: 3,
+    }
+  }
+}
+
+impl Config {
+  // The Lifeguard suspicion timeout, falling logarithmically with the
+  // confirmations, so the first few shorten it the most.
+  pub fn timeout(&self, confirmations: usize) -> Duration {
+    let (min, max) = (self.min_timeout.as_secs_f64(), self.max_timeout.as_secs_f64());
+    let fraction = ((confirmations + 1) as f64).ln() / ((self.confirmations + 1) as f64).ln();
+    return Duration::from_secs_f64(f64::max(min, max - (max - min) * fraction));
+  }
+}
+
+pub fn suspect_key(identifier: &str) -> String {
+  format!("{}{}", SUSPECT_PREFIX, identifier)
+}
+
+pub fn incarnation(node: &dyn Node) -> i64 {
+  match node.get(INCARNATION_KEY) {
+    Some(Value::Integer(incarnation)) => { *incarnation }
+    _ => { 0 }
+  }
+}
+
+// The incarnation of the peer the node suspects, if it does.
+pub fn suspicion_of(node: &dyn Node, identifier: &str) -> Option<i64> {
+  match node.get(suspect_key(identifier).as_str()) {
+    Some(Value::Integer(incarnation)) => { Some(*incarnation) }
+    _ => { None }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_timeout_shrinks_with_confirmations() {
+    let config = Config::default();
+    assert_eq!(config.timeout(0), Duration::from_secs(12));
+    assert_eq!(config.timeout(1), Duration::from_secs(7));
+    assert!(config.timeout(2) < Duration::from_secs(5));
+    assert_eq!(config.timeout(3), Duration::from_secs(2));
+    assert_eq!(config.timeout(10), Duration::from_secs(2));
+  }
+}
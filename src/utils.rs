@@ -1,6 +1,3 @@
-use oorandom;
-use getrandom;
-
 fn generate_seed() -> u128 {
   let mut bytes = [0u8; 16];
   if getrandom::getrandom(&mut bytes).is_err() {
@@ -12,25 +9,31 @@ fn generate_seed() -> u128 {
   bytes.iter().fold(0u128, |a, b| a << 8 | (*b as u128))
 }
 
+// Seconds since the unix epoch.
+pub fn timestamp() -> u64 {
+  use std::time::{SystemTime, UNIX_EPOCH};
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
 pub type Rng = oorandom::Rand64;
 
 pub fn rng(seed: Option<u128>) -> Rng {
-  Rng::new(seed.unwrap_or_else(|| generate_seed()))
+  Rng::new(seed.unwrap_or_else(generate_seed))
 }
 
 pub mod rand {
   // Fisher–Yates shuffle.
   // * Note: this modifies the input array.
-  pub fn shuffle<T>(rng: &mut super::Rng, array: &mut Vec<T>, max: usize) {
+  pub fn shuffle<T>(rng: &mut super::Rng, array: &mut [T], max: usize) {
     let len = array.len();
-    let m = usize::min(max, len - 2);
+    let m = usize::min(max, len.saturating_sub(1));
     for i in 0..m {
       let j = rng.rand_range(i as u64 .. len as u64) as usize;
       array.swap(i, j);
     }
   }
 
-  pub fn choose<'a, T>(rng: &mut super::Rng, array: &'a Vec<T>) -> &'a T {
+  pub fn choose<'a, T>(rng: &mut super::Rng, array: &'a [T]) -> &'a T {
     let index = rng.rand_range(0 .. array.len() as u64) as usize;
     return &array[index];
   }
@@ -62,20 +65,28 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_rand_shuffle_empty() {
+    let mut rng = rng(Some(42));
+    let mut array: Vec<u32> = Vec::new();
+    rand::shuffle(&mut rng, &mut array, 4);
+    assert!(array.is_empty());
+  }
+
   #[test]
   fn test_rand_choose() {
     let mut rng = rng(Some(42));
-    let mut array = vec![ 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15 ];
-    let choice = *rand::choose(&mut rng, &mut array);
+    let array = vec![ 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15 ];
+    let choice = *rand::choose(&mut rng, &array);
     assert_eq!(choice, 10);
   }
 }
 
 #[cfg(not(test))]
-use std::time::Instant;
+pub use std::time::Instant;
 
 #[cfg(test)]
-use mock_instant::thread_local::Instant;
+pub use mock_instant::thread_local::Instant;
 
 #[derive(Debug)]
 pub struct Touch(Instant);
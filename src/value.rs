@@ -55,6 +55,8 @@ impl From<f32> for Number {
   fn from(value: f32) -> Self { Self::Float(value as f64) }
 }
 
+// only used in tests so far
+#[allow(dead_code)]
 impl Number {
   fn is_unsigned(&self) -> bool {
     match self {
@@ -107,6 +109,8 @@ pub enum Value {
   Float(f64),
   Integers(Vec<i64>),
   Floats(Vec<f64>),
+  // marks a deleted key, so the deletion propagates like any other update
+  Tombstone,
 }
 
 impl From<String> for Value {
@@ -145,7 +149,7 @@ impl From<Vec<i32>> for Value {
 
 impl From<&Vec<i32>> for Value {
   fn from(value: &Vec<i32>) -> Self {
-    Self::Integers(value.into_iter().map(|&v| v.into()).collect())
+    Self::Integers(value.iter().map(|&v| v.into()).collect())
   }
 }
 
@@ -157,7 +161,7 @@ impl From<Vec<u32>> for Value {
 
 impl From<&Vec<u32>> for Value {
   fn from(value: &Vec<u32>) -> Self {
-    Self::Integers(value.into_iter().map(|&v| v.into()).collect())
+    Self::Integers(value.iter().map(|&v| v.into()).collect())
   }
 }
 
@@ -169,7 +173,7 @@ impl From<Vec<i16>> for Value {
 
 impl From<&Vec<i16>> for Value {
   fn from(value: &Vec<i16>) -> Self {
-    Self::Integers(value.into_iter().map(|&v| v.into()).collect())
+    Self::Integers(value.iter().map(|&v| v.into()).collect())
   }
 }
 
@@ -181,7 +185,7 @@ impl From<Vec<u16>> for Value {
 
 impl From<&Vec<u16>> for Value {
   fn from(value: &Vec<u16>) -> Self {
-    Self::Integers(value.into_iter().map(|&v| v.into()).collect())
+    Self::Integers(value.iter().map(|&v| v.into()).collect())
   }
 }
 
@@ -193,7 +197,7 @@ impl From<Vec<i8>> for Value {
 
 impl From<&Vec<i8>> for Value {
   fn from(value: &Vec<i8>) -> Self {
-    Self::Integers(value.into_iter().map(|&v| v.into()).collect())
+    Self::Integers(value.iter().map(|&v| v.into()).collect())
   }
 }
 
@@ -205,7 +209,7 @@ impl From<Vec<u8>> for Value {
 
 impl From<&Vec<u8>> for Value {
   fn from(value: &Vec<u8>) -> Self {
-    Self::Integers(value.into_iter().map(|&v| v.into()).collect())
+    Self::Integers(value.iter().map(|&v| v.into()).collect())
   }
 }
 
@@ -225,11 +229,18 @@ impl From<Vec<f32>> for Value {
 
 impl From<&Vec<f32>> for Value {
   fn from(value: &Vec<f32>) -> Self {
-    Self::Floats(value.into_iter().map(|&v| v.into()).collect())
+    Self::Floats(value.iter().map(|&v| v.into()).collect())
   }
 }
 
 impl Value {
+  pub fn is_tombstone(&self) -> bool {
+    match self {
+      Self::Tombstone => true,
+      _ => false
+    }
+  }
+
   pub fn as_string(&self) -> Option<&str> {
     match self {
       Self::String(v) => { Some(v) }